@@ -6,6 +6,9 @@ use crate::subtle::incorrect_hmac_blake2b as hash;
 
 pub use hash::KEY_LEN;
 
+/// Name of the keyed hash used by the hash domains, for display
+pub const NAME: &str = "BLAKE2b in an HMAC-like construction";
+
 // TODO Use a proper Dec interface
 #[derive(Clone, Debug)]
 pub struct HashDomain([u8; KEY_LEN]);
@@ -10,6 +10,9 @@ const_assert!(KEY_LEN == hash_domain::KEY_LEN);
 /// Authenticated encryption with associated data
 pub mod aead {
     pub use crate::subtle::chacha20poly1305_ietf::{decrypt, encrypt, KEY_LEN, NONCE_LEN, TAG_LEN};
+
+    /// Name of the algorithm, for display
+    pub const NAME: &str = "ChaCha20-Poly1305 (IETF)";
 }
 
 /// Authenticated encryption with associated data with a constant nonce
@@ -17,11 +20,20 @@ pub mod xaead {
     pub use crate::subtle::xchacha20poly1305_ietf::{
         decrypt, encrypt, KEY_LEN, NONCE_LEN, TAG_LEN,
     };
+
+    /// Name of the algorithm, for display
+    pub const NAME: &str = "XChaCha20-Poly1305 (IETF)";
 }
 
 pub mod hash_domain;
 
 pub mod kem {
+    pub use rosenpass_oqs::set_randombytes;
     pub use rosenpass_oqs::ClassicMceliece460896 as StaticKem;
     pub use rosenpass_oqs::Kyber512 as EphemeralKem;
+
+    /// Name of the [StaticKem] algorithm, for display
+    pub const STATIC_KEM_NAME: &str = "Classic McEliece 460896";
+    /// Name of the [EphemeralKem] algorithm, for display
+    pub const EPHEMERAL_KEM_NAME: &str = "Kyber-512";
 }
@@ -76,6 +76,7 @@
               coreutils
               findutils
               gawk
+              getent
               wireguard-tools
             ];
 
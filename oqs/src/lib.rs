@@ -19,3 +19,13 @@ macro_rules! oqs_call {
 mod kem_macro;
 oqs_kem!(kyber_512);
 oqs_kem!(classic_mceliece_460896);
+
+/// Make liboqs draw all its randomness from `randombytes` instead of the system's random
+/// number generator
+///
+/// This affects every key generation and encapsulation in the process from then on, so
+/// `randombytes` must be a cryptographically secure random number generator for all uses
+/// but deliberately reproducible ones.
+pub fn set_randombytes(randombytes: unsafe extern "C" fn(*mut u8, usize)) {
+    unsafe { ::oqs_sys::rand::OQS_randombytes_custom_algorithm(Some(randombytes)) }
+}
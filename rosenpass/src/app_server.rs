@@ -1,4 +1,6 @@
 use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
 
 use anyhow::Result;
 use log::{debug, error, info, warn};
@@ -7,28 +9,39 @@ use mio::Token;
 use rosenpass_util::file::fopen_w;
 
 use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
 use std::io::Write;
 
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::net::SocketAddrV4;
 use std::net::SocketAddrV6;
 use std::net::ToSocketAddrs;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 use std::process::Stdio;
 use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 
 use crate::{
     config::Verbosity,
+    log_redact::{self, redacted},
+    msgs::MsgType,
     protocol::{CryptoServer, MsgBuf, PeerPtr, SPk, SSk, SymKey, Timing},
 };
 use rosenpass_util::attempt;
-use rosenpass_util::b64::{b64_writer, fmt_b64};
+use rosenpass_util::b64::{b64_reader, b64_writer, fmt_b64};
 
 const IPV4_ANY_ADDR: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const IPV6_ANY_ADDR: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0);
@@ -43,12 +56,58 @@ fn ipv6_any_binding() -> SocketAddr {
     SocketAddr::V6(SocketAddrV6::new(IPV6_ANY_ADDR, 0, 0, 0))
 }
 
+/// Bind a socket to each of `addrs` on the first port of `lo..=hi` that is free on all of
+/// them, together with the port above it, which WireGuard is to listen on
+///
+/// The port above is only checked, not reserved, so WireGuard may still lose it to another
+/// program in between.
+fn bind_port_range(
+    addrs: &[SocketAddr],
+    (lo, hi): (u16, u16),
+) -> anyhow::Result<Vec<mio::net::UdpSocket>> {
+    ensure!(
+        lo <= hi && hi < u16::MAX,
+        "invalid port-range {lo}-{hi}, it must leave room for the WireGuard port above it"
+    );
+    let in_use = |e: &std::io::Error| e.kind() == std::io::ErrorKind::AddrInUse;
+    'ports: for port in lo..=hi {
+        let mut sockets = Vec::new();
+        for addr in addrs {
+            let (mut addr, mut wg_addr) = (*addr, *addr);
+            addr.set_port(port);
+            wg_addr.set_port(port + 1);
+            match mio::net::UdpSocket::bind(addr) {
+                Ok(socket) => sockets.push(socket),
+                Err(e) if in_use(&e) => continue 'ports,
+                Err(e) => return Err(e).with_context(|| format!("could not listen on {addr}")),
+            }
+            match std::net::UdpSocket::bind(wg_addr) {
+                Ok(_) => {}
+                Err(e) if in_use(&e) => continue 'ports,
+                Err(e) => return Err(e).with_context(|| format!("could not bind {wg_addr}")),
+            }
+        }
+        info!("listening on port {port}, the first free one of port-range {lo}-{hi}");
+        return Ok(sockets);
+    }
+    bail!("no port of port-range {lo}-{hi} is free, together with the one above it for WireGuard")
+}
+
 #[derive(Default, Debug)]
 pub struct AppPeer {
+    pub name: Option<String>,
     pub outfile: Option<PathBuf>,
     pub outwg: Option<WireguardOut>, // TODO make this a generic command
     pub initial_endpoint: Option<Endpoint>,
     pub current_endpoint: Option<Endpoint>,
+    /// When the current key was exchanged, if there is one
+    pub last_exchange: Option<Instant>,
+    /// Number of handshakes initiated with the peer, not counting retransmissions
+    pub handshake_attempts: u64,
+    /// Number of keys exchanged with the peer
+    pub exchanges: u64,
+    /// The last problem with this peer, shared with the threads passing keys to WireGuard
+    pub last_error: Arc<Mutex<Option<String>>>,
 }
 
 impl AppPeer {
@@ -67,6 +126,52 @@ pub struct WireguardOut {
     pub extra_params: Vec<String>,
 }
 
+impl WireguardOut {
+    /// Length of a WireGuard public key in bytes
+    pub const PK_LEN: usize = 32;
+
+    /// Check that `pk` is a base64 encoded WireGuard public key, so a broken
+    /// key is reported as such instead of as an obscure `wg set` failure
+    pub fn check_pk(&self) -> anyhow::Result<()> {
+        let mut pk = Vec::new();
+        b64_reader(self.pk.as_bytes())
+            .read_to_end(&mut pk)
+            .with_context(|| format!("WireGuard public key {:?} is not valid base64", self.pk))?;
+        ensure!(
+            pk.len() == Self::PK_LEN,
+            "WireGuard public key {:?} is {} bytes long instead of {}",
+            self.pk,
+            pk.len(),
+            Self::PK_LEN
+        );
+        Ok(())
+    }
+
+    /// Arguments of the `wg` call that sets the exchanged key as preshared
+    /// key, read from stdin; `extra_params` such as `persistent-keepalive`
+    /// are passed on as given, whether or not any others are set
+    pub fn wg_set_args(&self) -> Vec<&str> {
+        let mut args = vec![
+            "set",
+            &self.dev,
+            "peer",
+            &self.pk,
+            "preshared-key",
+            "/dev/stdin",
+        ];
+        args.extend(self.extra_params.iter().map(String::as_str));
+        args
+    }
+
+    /// Arguments of the `wg` call that configures the peer with `extra_params`
+    /// alone, leaving its preshared key untouched
+    pub fn wg_setup_args(&self) -> Vec<&str> {
+        let mut args = vec!["set", &self.dev, "peer", &self.pk];
+        args.extend(self.extra_params.iter().map(String::as_str));
+        args
+    }
+}
+
 /// Holds the state of the application, namely the external IO
 ///
 /// Responsible for file IO, network IO
@@ -80,6 +185,78 @@ pub struct AppServer {
     pub peers: Vec<AppPeer>,
     pub verbosity: Verbosity,
     pub all_sockets_drained: bool,
+    /// The last value of [REKEY_REQUESTS] seen
+    pub rekey_requests_seen: u64,
+    /// Peers to start a handshake with right away, see [request_rekey]
+    pub pending_rekeys: VecDeque<AppPeerPtr>,
+    /// When the server was created, for the uptime reported by the control socket
+    pub started: Instant,
+    /// Index of the socket handshakes are initiated from, see [AppServer::bind_source_address]
+    pub source_socket: Option<usize>,
+    /// See [AppServer::listen_control]
+    #[cfg(unix)]
+    pub control: Option<ControlSocket>,
+}
+
+/// The mio token of the control socket; the UDP sockets use their index
+#[cfg(unix)]
+const CONTROL_TOKEN: Token = Token(usize::MAX);
+
+/// How long a control socket client may take to send its request and read the reply
+///
+/// Requests are answered from the event loop, so a stuck client delays the key exchange
+/// by at most this long.
+#[cfg(unix)]
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A Unix domain socket answering requests about the state of an [AppServer]
+///
+/// The socket file is removed again when this is dropped.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct ControlSocket {
+    listener: mio::net::UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Counts the requests for an immediate key exchange with every peer, e.g. on `SIGUSR2`
+///
+/// A counter rather than a flag, so every [AppServer] in the process sees each request.
+pub static REKEY_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Ask every [AppServer] to exchange new keys with all of its peers right away
+pub fn request_rekey() {
+    REKEY_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Call [request_rekey] on `SIGUSR2`
+#[cfg(unix)]
+pub fn install_rekey_signal() -> Result<()> {
+    extern "C" fn on_sigusr2(_: libc::c_int) {
+        // only touches an atomic, which is async signal safe
+        request_rekey();
+    }
+
+    // SAFETY: the handler only performs an atomic increment
+    let previous = unsafe {
+        libc::signal(
+            libc::SIGUSR2,
+            on_sigusr2 as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+    ensure!(
+        previous != libc::SIG_ERR,
+        "could not install the SIGUSR2 handler: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(())
 }
 
 /// A socket pointer is an index assigned to a socket;
@@ -221,13 +398,22 @@ impl Endpoint {
         }
     }
 
-    fn addresses(&self) -> &[SocketAddr] {
+    pub fn addresses(&self) -> &[SocketAddr] {
         use Endpoint::*;
         match self {
             SocketBoundAddress { addr, .. } => slice::from_ref(addr),
             Discovery(host) => host.addresses(),
         }
     }
+
+    /// The endpoint as it appears in the log, see [log_redact]
+    pub fn log_display(&self) -> String {
+        if log_redact::is_enabled() {
+            log_redact::redacted_list(self.addresses())
+        } else {
+            format!("{self:?}")
+        }
+    }
 }
 
 /// Handles host-path discovery
@@ -262,6 +448,54 @@ pub struct HostPathDiscoveryEndpoint {
     addresses: Vec<SocketAddr>,
 }
 
+/// Parse a scoped IPv6 socket address such as `[fe80::1%eth0]:9999`, as used for
+/// link-local endpoints
+///
+/// The standard library only accepts a numeric scope id, so a zone given as interface
+/// name is resolved to the interface index here. Returns `None` for anything else.
+fn parse_scoped_v6(hostname: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let Some((host, port)) = hostname
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("]:"))
+    else {
+        return Ok(None);
+    };
+    let Some((addr, zone)) = host.split_once('%') else {
+        return Ok(None);
+    };
+    if zone.parse::<u32>().is_ok() {
+        return Ok(None);
+    }
+
+    let addr: Ipv6Addr = addr
+        .parse()
+        .with_context(|| format!("{addr:?} in endpoint {hostname:?} is no IPv6 address"))?;
+    let port: u16 = port
+        .parse()
+        .with_context(|| format!("{port:?} in endpoint {hostname:?} is no port"))?;
+    let scope_id =
+        interface_index(zone).with_context(|| format!("invalid zone in endpoint {hostname:?}"))?;
+    Ok(Some(SocketAddr::V6(SocketAddrV6::new(
+        addr, port, 0, scope_id,
+    ))))
+}
+
+/// The index of the network interface `ifname`
+#[cfg(unix)]
+fn interface_index(ifname: &str) -> anyhow::Result<u32> {
+    let name = std::ffi::CString::new(ifname)
+        .with_context(|| format!("{ifname:?} is not a valid network interface name"))?;
+    // SAFETY: `name` is a valid NUL terminated string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    ensure!(index != 0, "there is no network interface named {ifname:?}");
+    Ok(index)
+}
+
+#[cfg(not(unix))]
+fn interface_index(ifname: &str) -> anyhow::Result<u32> {
+    bail!("network interface names such as {ifname:?} are not supported on this system, use the interface index")
+}
+
 impl HostPathDiscoveryEndpoint {
     pub fn from_addresses(addresses: Vec<SocketAddr>) -> Self {
         let scouting_state = Cell::new((0, 0));
@@ -273,8 +507,12 @@ impl HostPathDiscoveryEndpoint {
 
     /// Lookup a hostname
     pub fn lookup(hostname: String) -> anyhow::Result<Self> {
+        let addresses = match parse_scoped_v6(&hostname)? {
+            Some(addr) => vec![addr],
+            None => ToSocketAddrs::to_socket_addrs(&hostname)?.collect(),
+        };
         Ok(Self {
-            addresses: ToSocketAddrs::to_socket_addrs(&hostname)?.collect(),
+            addresses,
             scouting_state: Cell::new((0, 0)),
         })
     }
@@ -307,7 +545,8 @@ impl HostPathDiscoveryEndpoint {
             .enumerate()
             .cycle()
             .skip(sock_off)
-            .take(srv.sockets.len());
+            .take(srv.sockets.len())
+            .filter(|(sock_no, _)| srv.source_socket.is_none_or(|no| no == *sock_no));
 
         for (addr_no, addr) in addrs.by_ref() {
             for (sock_no, sock) in sockets.by_ref() {
@@ -327,7 +566,11 @@ impl HostPathDiscoveryEndpoint {
                     .to_string()
                     .starts_with("Address family not supported by protocol");
                 if !ignore {
-                    warn!("Socket #{} refusing to send to {}: ", sock_no, addr);
+                    warn!(
+                        "Socket #{} refusing to send to {}: ",
+                        sock_no,
+                        redacted(addr)
+                    );
                 }
             }
         }
@@ -337,10 +580,21 @@ impl HostPathDiscoveryEndpoint {
 }
 
 impl AppServer {
+    /// Create a server listening on `addrs`
+    ///
+    /// If binding to one of the addresses fails, this fails as well, unless
+    /// `best_effort_listen` is set: then the address is skipped with a warning, as
+    /// long as at least one of them can be bound.
+    ///
+    /// With a `port_range`, the ports of `addrs` are ignored; the first port of the
+    /// range that is free on all of them, along with the port above it for WireGuard,
+    /// is used instead.
     pub fn new(
         sk: SSk,
         pk: SPk,
         addrs: Vec<SocketAddr>,
+        port_range: Option<(u16, u16)>,
+        best_effort_listen: bool,
         verbosity: Verbosity,
     ) -> anyhow::Result<Self> {
         // setup mio
@@ -348,9 +602,36 @@ impl AppServer {
         let events = mio::Events::with_capacity(8);
 
         // bind each SocketAddr to a socket
-        let maybe_sockets: Result<Vec<_>, _> =
-            addrs.into_iter().map(mio::net::UdpSocket::bind).collect();
-        let mut sockets = maybe_sockets?;
+        let addrs_given = addrs.len();
+        let mut sockets = Vec::new();
+        if let Some(range) = port_range {
+            sockets = bind_port_range(&addrs, range)?;
+        } else {
+            for addr in addrs {
+                match mio::net::UdpSocket::bind(addr) {
+                    Ok(socket) => sockets.push(socket),
+                    Err(e) if best_effort_listen => {
+                        warn!("could not listen on {addr}, skipping it: {e}")
+                    }
+                    Err(e) => return Err(e).with_context(|| format!("could not listen on {addr}")),
+                }
+            }
+        }
+        if best_effort_listen && sockets.len() < addrs_given {
+            ensure!(
+                !sockets.is_empty(),
+                "could not listen on any of the {addrs_given} addresses"
+            );
+            let bound = sockets
+                .iter()
+                .map(|socket| socket.local_addr().map(|addr| addr.to_string()))
+                .collect::<Result<Vec<_>, _>>()?;
+            info!(
+                "listening on {} of {addrs_given} addresses: {}",
+                sockets.len(),
+                bound.join(", ")
+            );
+        }
 
         // When no socket is specified, rosenpass should open one port on all
         // available interfaces best-effort. Here are the cases how this can possibly go:
@@ -381,7 +662,7 @@ impl AppServer {
         // [^openbsd]: https://man.openbsd.org/ip6.4
         // [^linux]: https://man7.org/linux/man-pages/man7/ipv6.7.html
         // [^mio]: https://docs.rs/mio/0.8.6/mio/net/struct.UdpSocket.html#method.only_v6
-        if sockets.is_empty() {
+        if addrs_given == 0 {
             macro_rules! try_register_socket {
                 ($title:expr, $binding:expr) => {{
                     let r = mio::net::UdpSocket::bind($binding);
@@ -435,6 +716,12 @@ impl AppServer {
             events,
             mio_poll,
             all_sockets_drained: false,
+            rekey_requests_seen: REKEY_REQUESTS.load(Ordering::Relaxed),
+            pending_rekeys: VecDeque::new(),
+            started: Instant::now(),
+            source_socket: None,
+            #[cfg(unix)]
+            control: None,
         })
     }
 
@@ -442,6 +729,163 @@ impl AppServer {
         matches!(self.verbosity, Verbosity::Verbose)
     }
 
+    /// The addresses the sockets are actually bound to
+    ///
+    /// Unlike the addresses passed to [`AppServer::new`], these contain the
+    /// port chosen by the operating system when binding to port 0.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self
+            .sockets
+            .iter()
+            .map(|socket| socket.local_addr())
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Initiate handshakes from `addr` rather than from the address the system picks
+    ///
+    /// This binds another socket to `addr` on a port chosen by the system; the replies to
+    /// the initiations arrive there as well. Peers contacting us still reach the sockets
+    /// given to [`AppServer::new`], and we answer them from those.
+    pub fn bind_source_address(&mut self, addr: IpAddr) -> Result<()> {
+        let mut socket =
+            mio::net::UdpSocket::bind(SocketAddr::new(addr, 0)).with_context(|| {
+                format!("could not bind source-address {addr}, it must be an address of this host")
+            })?;
+        let no = self.sockets.len();
+        self.mio_poll
+            .registry()
+            .register(&mut socket, Token(no), Interest::READABLE)?;
+        self.sockets.push(socket);
+        self.source_socket = Some(no);
+        Ok(())
+    }
+
+    /// Restrict all sockets to traffic through the network interface `ifname`
+    ///
+    /// This uses `SO_BINDTODEVICE` on Linux; on other systems it only logs a warning.
+    #[cfg(target_os = "linux")]
+    pub fn bind_device(&self, ifname: &str) -> Result<()> {
+        use std::os::fd::AsRawFd;
+
+        ensure!(
+            !ifname.is_empty() && ifname.len() < libc::IFNAMSIZ && !ifname.contains('\0'),
+            "{ifname:?} is not a valid network interface name"
+        );
+        for socket in self.sockets.iter() {
+            // SAFETY: the file descriptor is owned by `socket`, and the option value
+            // points to `ifname.len()` valid bytes, as required by SO_BINDTODEVICE
+            let r = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_BINDTODEVICE,
+                    ifname.as_ptr() as *const libc::c_void,
+                    ifname.len() as libc::socklen_t,
+                )
+            };
+            if r != 0 {
+                bail!(
+                    "could not bind socket {} to device {ifname}: {}",
+                    socket.local_addr()?,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Restrict all sockets to traffic through the network interface `ifname`
+    ///
+    /// This uses `SO_BINDTODEVICE` on Linux; on other systems it only logs a warning.
+    #[cfg(not(target_os = "linux"))]
+    pub fn bind_device(&self, ifname: &str) -> Result<()> {
+        warn!("binding to device {ifname} is only supported on Linux, ignoring");
+        Ok(())
+    }
+
+    /// Set the size of the receive (`SO_RCVBUF`) and send (`SO_SNDBUF`) buffers of all
+    /// sockets, logging the size the system actually applied
+    ///
+    /// The system may clamp the sizes; on Linux, sizes above `net.core.rmem_max` and
+    /// `net.core.wmem_max` cause a warning.
+    #[cfg(unix)]
+    pub fn set_socket_buffers(&self, rcvbuf: Option<usize>, sndbuf: Option<usize>) -> Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let options = [
+            (rcvbuf, libc::SO_RCVBUF, "receive", "rmem_max"),
+            (sndbuf, libc::SO_SNDBUF, "send", "wmem_max"),
+        ];
+        for (size, option, what, sysctl) in options {
+            let Some(size) = size else { continue };
+            let Ok(value) = libc::c_int::try_from(size) else {
+                bail!("{what} buffer size {size} is too large");
+            };
+
+            let max = std::fs::read_to_string(format!("/proc/sys/net/core/{sysctl}"));
+            if let Some(max) = max.ok().and_then(|m| m.trim().parse::<usize>().ok()) {
+                if size > max {
+                    warn!(
+                        "{what} buffer size {size} exceeds the system maximum of {max} \
+                        (net.core.{sysctl}), the system will reduce it"
+                    );
+                }
+            }
+
+            for socket in self.sockets.iter() {
+                let fd = socket.as_raw_fd();
+                // SAFETY: the file descriptor is owned by `socket`, and the option value
+                // points to a valid c_int, as required by SO_RCVBUF and SO_SNDBUF
+                let r = unsafe {
+                    libc::setsockopt(
+                        fd,
+                        libc::SOL_SOCKET,
+                        option,
+                        &value as *const libc::c_int as *const libc::c_void,
+                        std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                    )
+                };
+                if r != 0 {
+                    bail!(
+                        "could not set the {what} buffer size of socket {} to {size}: {}",
+                        socket.local_addr()?,
+                        std::io::Error::last_os_error()
+                    );
+                }
+
+                let mut applied: libc::c_int = 0;
+                let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+                // SAFETY: as above, and `len` holds the size of `applied`
+                let r = unsafe {
+                    libc::getsockopt(
+                        fd,
+                        libc::SOL_SOCKET,
+                        option,
+                        &mut applied as *mut libc::c_int as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+                if r == 0 {
+                    info!(
+                        "{what} buffer of socket {} is {applied} bytes (requested {size})",
+                        socket.local_addr()?
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the size of the receive (`SO_RCVBUF`) and send (`SO_SNDBUF`) buffers of all
+    /// sockets; on this system this only logs a warning
+    #[cfg(not(unix))]
+    pub fn set_socket_buffers(&self, rcvbuf: Option<usize>, sndbuf: Option<usize>) -> Result<()> {
+        if rcvbuf.is_some() || sndbuf.is_some() {
+            warn!("setting socket buffer sizes is not supported on this system, ignoring");
+        }
+        Ok(())
+    }
+
     pub fn add_peer(
         &mut self,
         psk: Option<SymKey>,
@@ -449,7 +893,11 @@ impl AppServer {
         outfile: Option<PathBuf>,
         outwg: Option<WireguardOut>,
         hostname: Option<String>,
+        name: Option<String>,
     ) -> anyhow::Result<AppPeerPtr> {
+        if let Some(wg) = &outwg {
+            wg.check_pk()?;
+        }
         let PeerPtr(pn) = self.crypt.add_peer(psk, pk)?;
         assert!(pn == self.peers.len());
         let initial_endpoint = hostname
@@ -457,12 +905,34 @@ impl AppServer {
             .transpose()?;
         let current_endpoint = None;
         self.peers.push(AppPeer {
+            name,
             outfile,
             outwg,
             initial_endpoint,
             current_endpoint,
+            ..Default::default()
         });
-        Ok(AppPeerPtr(pn))
+        let peer = AppPeerPtr(pn);
+
+        // Both identities side by side, so a wgpk and pqpk that do not belong
+        // together are easy to spot
+        if self.verbose() {
+            let label = self.peer_label(peer)?;
+            let pq_id = fmt_b64(&*peer.lower().get(&self.crypt).pidt()?).to_string();
+            match &peer.get_app(self).outwg {
+                Some(wg) => info!(
+                    "peer {label}: rosenpass id {}, WireGuard public key {} on {}",
+                    redacted(pq_id),
+                    redacted(&wg.pk),
+                    wg.dev
+                ),
+                None => info!(
+                    "peer {label}: rosenpass id {}, no WireGuard output",
+                    redacted(pq_id)
+                ),
+            }
+        }
+        Ok(peer)
     }
 
     pub fn listen_loop(&mut self) -> anyhow::Result<()> {
@@ -527,15 +997,31 @@ impl AppServer {
             use KeyOutputReason::*;
             match self.poll(&mut *rx)? {
                 #[allow(clippy::redundant_closure_call)]
-                SendInitiation(peer) => tx_maybe_with!(peer, || self
-                    .crypt
-                    .initiate_handshake(peer.lower(), &mut *tx))?,
+                SendInitiation(peer) => {
+                    if peer.get_app(self).endpoint().is_some() {
+                        self.log_stage(peer, "initiating handshake with");
+                        peer.get_app_mut(self).handshake_attempts += 1;
+                    }
+                    tx_maybe_with!(peer, || self
+                        .crypt
+                        .initiate_handshake(peer.lower(), &mut *tx))?
+                }
                 #[allow(clippy::redundant_closure_call)]
-                SendRetransmission(peer) => tx_maybe_with!(peer, || self
-                    .crypt
-                    .retransmit_handshake(peer.lower(), &mut *tx))?,
+                SendRetransmission(peer) => {
+                    if peer.get_app(self).endpoint().is_some() {
+                        self.log_stage(peer, "retransmitting handshake message to");
+                    }
+                    tx_maybe_with!(peer, || self
+                        .crypt
+                        .retransmit_handshake(peer.lower(), &mut *tx))?
+                }
                 DeleteKey(peer) => {
                     self.output_key(peer, Stale, &SymKey::random())?;
+                    let p = peer.get_app_mut(self);
+                    if p.last_exchange.take().is_some() {
+                        *p.last_error.lock().unwrap() =
+                            Some("key expired without a new key exchange".to_owned());
+                    }
 
                     // There was a loss of connection apparently; restart host discovery
                     // starting from the last used address but including all the initially
@@ -549,12 +1035,20 @@ impl AppServer {
                 }
 
                 ReceivedMessage(len, endpoint) => {
+                    if self.verbose() {
+                        let from = endpoint.log_display();
+                        match rx.first().map(|&t| MsgType::try_from(t)) {
+                            Some(Ok(msg_type)) => info!("received {msg_type:?} from {from}"),
+                            _ => info!("received unknown message from {from}"),
+                        }
+                    }
+
                     match self.crypt.handle_msg(&rx[..len], &mut *tx) {
                         Err(ref e) => {
                             self.verbose().then(|| {
                                 info!(
-                                    "error processing incoming message from {:?}: {:?} {}",
-                                    endpoint,
+                                    "error processing incoming message from {}: {:?} {}",
+                                    endpoint.log_display(),
                                     e,
                                     e.backtrace()
                                 );
@@ -567,12 +1061,25 @@ impl AppServer {
                             ..
                         }) => {
                             if let Some(len) = resp {
+                                if self.verbose() {
+                                    if let Some(Ok(msg_type)) =
+                                        tx.first().map(|&t| MsgType::try_from(t))
+                                    {
+                                        info!(
+                                            "responding with {msg_type:?} to {}",
+                                            endpoint.log_display()
+                                        );
+                                    }
+                                }
                                 endpoint.send(self, &tx[0..len])?;
                             }
 
                             if let Some(p) = exchanged_with {
                                 let ap = AppPeerPtr::lift(p);
-                                ap.get_app_mut(self).current_endpoint = Some(endpoint);
+                                let app_peer = ap.get_app_mut(self);
+                                app_peer.current_endpoint = Some(endpoint);
+                                app_peer.last_exchange = Some(Instant::now());
+                                app_peer.exchanges += 1;
 
                                 // TODO: Maybe we should rather call the key "rosenpass output"?
                                 self.output_key(ap, Exchanged, &self.crypt.osk(p)?)?;
@@ -584,6 +1091,74 @@ impl AppServer {
         }
     }
 
+    /// The name of `peer` for logs; its configured name or else its peer id, [redacted]
+    fn peer_label(&self, peer: AppPeerPtr) -> anyhow::Result<String> {
+        match peer.get_app(self).name.as_ref() {
+            Some(name) => Ok(redacted(name)),
+            None => Ok(redacted(fmt_b64(&*peer.lower().get(&self.crypt).pidt()?))),
+        }
+    }
+
+    /// Log a handshake stage concerning `peer`, if verbose
+    fn log_stage(&self, peer: AppPeerPtr, stage: &str) {
+        if !self.verbose() {
+            return;
+        }
+        if let Ok(label) = self.peer_label(peer) {
+            info!("{stage} peer {label}");
+        }
+    }
+
+    /// Let the WireGuard devices of all peers listen on `port`
+    ///
+    /// Used with a port range, where the WireGuard port follows from the one picked for
+    /// rosenpass only once it is bound.
+    pub fn set_wireguard_listen_port(&self, port: u16) -> anyhow::Result<()> {
+        let devs: std::collections::BTreeSet<_> = self
+            .peers
+            .iter()
+            .filter_map(|ap| ap.outwg.as_ref().map(|owg| owg.dev.as_str()))
+            .collect();
+        for dev in devs {
+            let status = Command::new("wg")
+                .args(["set", dev, "listen-port", &port.to_string()])
+                .status()
+                .with_context(|| format!("could not run wg to set the port of {dev}"))?;
+            ensure!(
+                status.success(),
+                "could not let WireGuard device {dev} listen on port {port}: wg {status}"
+            );
+            info!("WireGuard device {dev} listens on port {port}");
+        }
+        Ok(())
+    }
+
+    /// Configure every peer with a WireGuard output in WireGuard, without a preshared key
+    ///
+    /// Used instead of the [Self::event_loop] by `setup-only`; the `wg` calls are waited
+    /// for, so the peers are in place once this returns.
+    pub fn setup_wireguard(&self) -> anyhow::Result<()> {
+        for (no, ap) in self.peers.iter().enumerate() {
+            let Some(owg) = ap.outwg.as_ref() else {
+                continue;
+            };
+            let label = self.peer_label(AppPeerPtr(no))?;
+            let status = Command::new("wg")
+                .args(owg.wg_setup_args())
+                .status()
+                .with_context(|| format!("could not run wg to configure peer {label}"))?;
+            ensure!(
+                status.success(),
+                "could not configure peer {label} on WireGuard device {}: wg {status}",
+                owg.dev
+            );
+            if self.verbose() {
+                info!("configured peer {label} on WireGuard device {}", owg.dev);
+            }
+        }
+        Ok(())
+    }
+
     pub fn output_key(
         &self,
         peer: AppPeerPtr,
@@ -598,7 +1173,7 @@ impl AppServer {
                 KeyOutputReason::Exchanged => "Exchanged key with peer",
                 KeyOutputReason::Stale => "Erasing outdated key from peer",
             };
-            info!("{} {}", msg, fmt_b64(&*peerid));
+            info!("{} {}", msg, self.peer_label(peer)?);
         }
 
         if let Some(of) = ap.outfile.as_ref() {
@@ -625,29 +1200,33 @@ impl AppServer {
 
         if let Some(owg) = ap.outwg.as_ref() {
             let mut child = Command::new("wg")
-                .arg("set")
-                .arg(&owg.dev)
-                .arg("peer")
-                .arg(&owg.pk)
-                .arg("preshared-key")
-                .arg("/dev/stdin")
+                .args(owg.wg_set_args())
                 .stdin(Stdio::piped())
-                .args(&owg.extra_params)
                 .spawn()?;
             b64_writer(child.stdin.take().unwrap()).write_all(key.secret())?;
 
+            let verbose = self.verbose();
+            let (dev, label) = (owg.dev.clone(), self.peer_label(peer)?);
+            let last_error = ap.last_error.clone();
             thread::spawn(move || {
                 let status = child.wait();
 
-                if let Ok(status) = status {
+                let problem = if let Ok(status) = status {
                     if status.success() {
-                        debug!("successfully passed psk to wg")
-                    } else {
-                        error!("could not pass psk to wg {:?}", status)
+                        if verbose {
+                            info!("passed key of peer {label} to WireGuard device {dev}");
+                        } else {
+                            debug!("successfully passed psk to wg")
+                        }
+                        return;
                     }
+                    error!("could not pass psk to wg {:?}", status);
+                    format!("could not pass the key to WireGuard device {dev}: wg {status}")
                 } else {
-                    error!("wait failed: {:?}", status)
-                }
+                    error!("wait failed: {:?}", status);
+                    format!("could not pass the key to WireGuard device {dev}: {status:?}")
+                };
+                *last_error.lock().unwrap() = Some(problem);
             });
         }
 
@@ -658,6 +1237,18 @@ impl AppServer {
         use crate::protocol::PollResult as C;
         use AppPollResult as A;
         loop {
+            let rekey_requests = REKEY_REQUESTS.load(Ordering::Relaxed);
+            if rekey_requests != self.rekey_requests_seen {
+                self.rekey_requests_seen = rekey_requests;
+                if self.verbose() {
+                    info!("key exchange with all peers requested");
+                }
+                self.pending_rekeys = (0..self.peers.len()).map(AppPeerPtr).collect();
+            }
+            if let Some(peer) = self.pending_rekeys.pop_front() {
+                return Ok(A::SendInitiation(peer));
+            }
+
             return Ok(match self.crypt.poll()? {
                 C::DeleteKey(PeerPtr(no)) => A::DeleteKey(AppPeerPtr(no)),
                 C::SendInitiation(PeerPtr(no)) => A::SendInitiation(AppPeerPtr(no)),
@@ -705,10 +1296,17 @@ impl AppServer {
         // readiness event seems to be good enough™ for now.
 
         // only poll if we drained all sockets before
+        // a signal, such as the one requesting a rekey, interrupts the wait
         if self.all_sockets_drained {
-            self.mio_poll.poll(&mut self.events, Some(timeout))?;
+            match self.mio_poll.poll(&mut self.events, Some(timeout)) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(None),
+                res => res?,
+            }
         }
 
+        #[cfg(unix)]
+        self.serve_control();
+
         let mut would_block_count = 0;
         for (sock_no, socket) in self.sockets.iter_mut().enumerate() {
             match socket.recv_from(buf) {
@@ -737,3 +1335,219 @@ impl AppServer {
         Ok(None)
     }
 }
+
+/// The control socket
+///
+/// Each client sends a single line with its request and gets the reply, after which the
+/// connection is closed:
+///
+/// - `status`: `uptime <SECS>`, `peers <N>` and `exchanges <N>`, one per line
+/// - `peers`: a line `peer <LABEL> key-age <SECS>|none handshake-attempts <N> exchanges <N>
+///   endpoint <ADDR>[,<ADDR>]...|none last-error <TEXT>|none` for each peer
+///
+/// Anything else is answered with a line starting with `error`.
+#[cfg(unix)]
+impl AppServer {
+    /// Serve requests about the state of this server on the Unix domain socket `path`
+    ///
+    /// A socket left behind at `path`, e.g. by a process that was killed, is replaced;
+    /// any other kind of file is not.
+    pub fn listen_control(&mut self, path: &Path) -> Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            ensure!(
+                meta.file_type().is_socket(),
+                "control socket {path:?} exists and is not a socket"
+            );
+            std::fs::remove_file(path)
+                .with_context(|| format!("could not remove stale control socket {path:?}"))?;
+        }
+
+        let mut listener = mio::net::UnixListener::bind(path)
+            .with_context(|| format!("could not bind control socket {path:?}"))?;
+        self.mio_poll
+            .registry()
+            .register(&mut listener, CONTROL_TOKEN, Interest::READABLE)?;
+        self.control = Some(ControlSocket {
+            listener,
+            path: path.to_owned(),
+        });
+        Ok(())
+    }
+
+    /// Answer every client waiting on the control socket; problems are only logged, so
+    /// they never stop the key exchange
+    fn serve_control(&mut self) {
+        loop {
+            let Some(control) = self.control.as_ref() else {
+                return;
+            };
+            let stream = match control.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("could not accept a control socket connection: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = self.answer_control(stream) {
+                warn!("could not answer a control socket request: {e:#}");
+            }
+        }
+    }
+
+    fn answer_control(&self, stream: mio::net::UnixStream) -> Result<()> {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+        // SAFETY: the file descriptor was just released by the mio stream
+        let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(stream.into_raw_fd()) };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+
+        let mut request = String::new();
+        BufReader::new(&stream).take(256).read_line(&mut request)?;
+        let reply = match request.trim() {
+            "status" => self.control_status(),
+            "peers" => self.control_peers()?,
+            other => format!("error unknown request {other:?}, expected status or peers\n"),
+        };
+        (&stream).write_all(reply.as_bytes())?;
+        Ok(())
+    }
+
+    fn control_status(&self) -> String {
+        let exchanges: u64 = self.peers.iter().map(|p| p.exchanges).sum();
+        format!(
+            "uptime {}\npeers {}\nexchanges {exchanges}\n",
+            self.started.elapsed().as_secs(),
+            self.peers.len()
+        )
+    }
+
+    fn control_peers(&self) -> Result<String> {
+        let mut reply = String::new();
+        for (no, p) in self.peers.iter().enumerate() {
+            let key_age = match p.last_exchange {
+                Some(at) => at.elapsed().as_secs().to_string(),
+                None => "none".to_owned(),
+            };
+            let endpoint = match p.endpoint().map(Endpoint::addresses) {
+                Some(addrs) if !addrs.is_empty() => log_redact::redacted_list(addrs),
+                _ => "none".to_owned(),
+            };
+            let last_error = p.last_error.lock().unwrap().clone();
+            reply += &format!(
+                "peer {} key-age {key_age} handshake-attempts {} exchanges {} endpoint {endpoint} last-error {}\n",
+                self.peer_label(AppPeerPtr(no))?,
+                p.handshake_attempts,
+                p.exchanges,
+                last_error.as_deref().unwrap_or("none"),
+            );
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn wg_out(pk: &str) -> WireguardOut {
+        WireguardOut {
+            dev: "rosenpass0".to_string(),
+            pk: pk.to_string(),
+            extra_params: vec![],
+        }
+    }
+
+    #[test]
+    fn wireguard_pk_is_checked() {
+        assert!(wg_out("yY3FIcdkBdaAmRTtuzfSbHsEpA6a6cBC5JYSLWAUEXk=")
+            .check_pk()
+            .is_ok());
+        // Not base64
+        assert!(wg_out("not a key").check_pk().is_err());
+        // Valid base64, but 16 bytes
+        assert!(wg_out("AAAAAAAAAAAAAAAAAAAAAA==").check_pk().is_err());
+        assert!(wg_out("").check_pk().is_err());
+    }
+
+    #[test]
+    fn scoped_ipv6_endpoint() {
+        // resolved by the standard library
+        assert!(parse_scoped_v6("[fe80::1%2]:9999").unwrap().is_none());
+        assert!(parse_scoped_v6("[fe80::1]:9999").unwrap().is_none());
+        assert!(parse_scoped_v6("peer.test:9999").unwrap().is_none());
+
+        #[cfg(target_os = "linux")]
+        {
+            let lo = interface_index("lo").unwrap();
+            let scoped = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 9999, 0, lo));
+            assert_eq!(parse_scoped_v6("[fe80::1%lo]:9999").unwrap(), Some(scoped));
+            // the zone survives the lookup of the endpoint
+            let host = HostPathDiscoveryEndpoint::lookup("[fe80::1%lo]:9999".into()).unwrap();
+            assert_eq!(host.addresses(), &vec![scoped]);
+        }
+
+        assert!(parse_scoped_v6("[fe80::1%no-such-if0]:9999").is_err());
+        assert!(parse_scoped_v6("[fe80::1%lo]:99999").is_err());
+        assert!(parse_scoped_v6("[not-an-ip%lo]:9999").is_err());
+    }
+
+    #[test]
+    fn wireguard_keepalive_without_allowed_ips() {
+        let wg = WireguardOut {
+            extra_params: vec!["persistent-keepalive".into(), "25".into()],
+            ..wg_out("yY3FIcdkBdaAmRTtuzfSbHsEpA6a6cBC5JYSLWAUEXk=")
+        };
+        assert_eq!(
+            wg.wg_set_args(),
+            vec![
+                "set",
+                "rosenpass0",
+                "peer",
+                "yY3FIcdkBdaAmRTtuzfSbHsEpA6a6cBC5JYSLWAUEXk=",
+                "preshared-key",
+                "/dev/stdin",
+                "persistent-keepalive",
+                "25",
+            ]
+        );
+    }
+
+    #[test]
+    fn port_range_skips_used_ports() {
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let addrs = ["127.0.0.1:0".parse().unwrap()];
+
+        // neither the port itself nor the one below it, whose WireGuard port it is, fit
+        let sockets = bind_port_range(&addrs, (port - 1, port + 8)).unwrap();
+        let picked = sockets[0].local_addr().unwrap().port();
+        assert!(picked > port, "{picked} <= {port}");
+
+        assert!(bind_port_range(&addrs, (port, port)).is_err());
+        assert!(bind_port_range(&addrs, (port, port - 1)).is_err());
+    }
+
+    #[test]
+    fn wireguard_setup_without_psk() {
+        let wg = WireguardOut {
+            extra_params: vec!["allowed-ips".into(), "10.0.0.2/32".into()],
+            ..wg_out("yY3FIcdkBdaAmRTtuzfSbHsEpA6a6cBC5JYSLWAUEXk=")
+        };
+        assert_eq!(
+            wg.wg_setup_args(),
+            vec![
+                "set",
+                "rosenpass0",
+                "peer",
+                "yY3FIcdkBdaAmRTtuzfSbHsEpA6a6cBC5JYSLWAUEXk=",
+                "allowed-ips",
+                "10.0.0.2/32",
+            ]
+        );
+    }
+}
@@ -1,16 +1,110 @@
-use anyhow::{bail, ensure};
+use anyhow::{bail, ensure, Context};
 use clap::{Parser, Subcommand};
 use rosenpass_cipher_traits::Kem;
 use rosenpass_ciphers::kem::StaticKem;
 use rosenpass_secret_memory::file::StoreSecret;
 use rosenpass_util::file::{LoadValue, LoadValueB64};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use crate::app_server;
 use crate::app_server::AppServer;
-use crate::protocol::{SPk, SSk, SymKey};
+use crate::key_layout;
+#[cfg(unix)]
+use crate::key_source::AgentKeySource;
+use crate::key_source::{FileKeySource, KeySource};
+use crate::log_redact::{self, redacted};
+use crate::protocol::{CryptoServer, MsgBuf, PeerPtr, SPk, SSk, SymKey};
+use crate::seeded_keygen;
 
 use super::config;
+use super::config::Verbosity;
+
+/// errors arising from parsing arguments that are not handled by `clap`
+#[derive(thiserror::Error, Debug)]
+pub enum CliError {
+    #[error("unrecognised argument {0}")]
+    UnrecognisedArgument(String),
+    #[error("{0} must be set")]
+    MissingArgument(&'static str),
+    #[error("{0} was already set")]
+    AlreadySet(&'static str),
+    #[error("{addr} can not be parsed to a socket address: {source}")]
+    InvalidSocketAddr {
+        addr: String,
+        source: std::io::Error,
+    },
+    #[error("{addr} can not be parsed to an IP address: {source}")]
+    InvalidIpAddr {
+        addr: String,
+        source: std::net::AddrParseError,
+    },
+    #[error("{option} {value:?} is not a number: {source}")]
+    InvalidNumber {
+        option: &'static str,
+        value: String,
+        source: std::num::ParseFloatError,
+    },
+    #[error("{option} {value:?} is not a size in bytes: {source}")]
+    InvalidSize {
+        option: &'static str,
+        value: String,
+        source: std::num::ParseIntError,
+    },
+    #[error("port-range {0:?} is not of the form <lo>-<hi>")]
+    InvalidPortRange(String),
+    #[error("{option} {value:?} is not a count: {source}")]
+    InvalidCount {
+        option: &'static str,
+        value: String,
+        source: std::num::ParseIntError,
+    },
+    #[error("{value:?} refers to the undefined environment variable {name}")]
+    UndefinedVariable { name: String, value: String },
+    #[error("{0:?} contains an unterminated or empty ${{...}}")]
+    InvalidInterpolation(String),
+}
+
+/// expand `${VAR}` and `${VAR:-fallback}` in `value` from the process environment
+///
+/// An undefined variable is an error unless it has a fallback, which is also used for a
+/// variable that is defined but empty. A `$` not followed by `{` is left as it is.
+pub fn interpolate_env(value: &str) -> Result<String, CliError> {
+    interpolate(value, |name| std::env::var(name).ok())
+}
+
+fn interpolate(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, CliError> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| CliError::InvalidInterpolation(value.to_owned()))?;
+        let (name, fallback) = match after[..end].split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (&after[..end], None),
+        };
+        if name.is_empty() {
+            return Err(CliError::InvalidInterpolation(value.to_owned()));
+        }
+        match (lookup(name), fallback) {
+            (Some(v), Some(fallback)) if v.is_empty() => out.push_str(fallback),
+            (Some(v), _) => out.push_str(&v),
+            (None, Some(fallback)) => out.push_str(fallback),
+            (None, None) => {
+                return Err(CliError::UndefinedVariable {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                })
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
 
 /// struct holding all CLI arguments for `clap` crate to parse
 #[derive(Parser, Debug)]
@@ -28,6 +122,11 @@ pub struct CliArgs {
     #[arg(short, long, group = "log-level")]
     quiet: bool,
 
+    /// append the log to this file instead of writing it to stderr; the file is opened again
+    /// on SIGHUP, so it can be rotated
+    #[arg(long = "log-file", value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: CliCommand,
 }
@@ -62,7 +161,18 @@ pub enum CliCommand {
     /// with the specified peers. If a peer's endpoint is specified, this
     /// Rosenpass instance will try to initiate a key exchange with the peer,
     /// otherwise only initiation attempts from the peer will be responded to.
-    ExchangeConfig { config_file: PathBuf },
+    ///
+    /// Several configuration files run as independent tunnels in one process,
+    /// each with its own keys, sockets and peers; the process exits as soon
+    /// as one of them fails.
+    ///
+    /// A configuration file written for a newer rosenpass that is incompatible by the
+    /// rules of semantic versioning, as recorded in its `version`, is refused; options this
+    /// version does not understand are listed in a warning.
+    ExchangeConfig {
+        #[clap(required = true)]
+        config_files: Vec<PathBuf>,
+    },
 
     /// Start in daemon mode, performing key exchanges
     ///
@@ -77,11 +187,13 @@ pub enum CliCommand {
     #[allow(rustdoc::broken_intra_doc_links)]
     #[allow(rustdoc::invalid_html_tags)]
     Exchange {
-        /// public-key <PATH> secret-key <PATH> [listen <ADDR>:<PORT>]... [verbose]
+        /// public-key <PATH> (secret-key <PATH> | agent-socket <PATH>) [listen <ADDR>:<PORT>]... [verbose] [rekey-interval <SECS>] [handshake-timeout <SECS>] [skip-bad-peers] [best-effort-listen] [setup-only] [redact] [bind-device <IFNAME>] [rcvbuf <BYTES>] [sndbuf <BYTES>] [control-socket <PATH>] [source-address <IP>] [max-peers <N>] [port-range <LO>-<HI>] [log-file <PATH>] [peers-stdin]
         #[clap(value_name = "OWN_CONFIG")]
         first_arg: String,
 
-        /// peer public-key <PATH> [ENDPOINT] [PSK] [OUTFILE] [WG]
+        /// peer public-key <PATH> [NAME] [ENDPOINT] [PSK] [OUTFILE] [WG]
+        ///
+        /// NAME := name <LABEL>
         ///
         /// ENDPOINT := endpoint <HOST/IP>:<PORT>
         ///
@@ -126,6 +238,11 @@ pub enum CliCommand {
         /// Forcefully overwrite public- & secret-key file
         #[clap(short, long)]
         force: bool,
+
+        /// INSECURE, for tests and recovery only: derive the keys from this seed of
+        /// 64 hexadecimal digits, so the same seed always yields the same keys
+        #[clap(long, value_name = "HEX")]
+        seed: Option<String>,
     },
 
     /// Deprecated - use gen-keys instead
@@ -137,9 +254,69 @@ pub enum CliCommand {
         args: Vec<String>,
     },
 
+    /// Convert a secret key directory between its layouts
+    ///
+    /// A directory holding `pqsk`, `pqpk` and `wgsk`, as `rp genkey` creates it, is written
+    /// to TO as a single combined file; a combined file is written to TO as such a directory.
+    /// The combined file is only meant for copying and backing up keys: neither `rosenpass`
+    /// nor `rp` load keys from it, so convert it back to a directory before using the keys.
+    /// See the [key_layout](crate::key_layout) module for the combined file.
+    Convert {
+        from: PathBuf,
+        to: PathBuf,
+
+        /// Forcefully overwrite TO if it exists
+        #[clap(short, long)]
+        force: bool,
+    },
+
     /// Validate a configuration
     Validate { config_files: Vec<PathBuf> },
 
+    /// Query the control socket of a running exchange
+    ///
+    /// Prints the reply to REQUEST: `status` for an overview, `peers` for the state
+    /// of every peer.
+    #[cfg(unix)]
+    Status {
+        socket: PathBuf,
+
+        #[clap(default_value = "status")]
+        request: String,
+    },
+
+    /// Ask a key agent for a key and print it
+    ///
+    /// KEY is `wgsk` for the base64 encoded WireGuard private key, see
+    /// the [key_source](crate::key_source) module for the protocol.
+    #[cfg(unix)]
+    AgentKey { socket: PathBuf, key: String },
+
+    /// Show the cryptographic algorithms and key sizes of this build
+    Info,
+
+    /// Check that the cryptography works on this platform
+    ///
+    /// Generates two keypairs, stores and reloads one of them and runs a
+    /// handshake between the two entirely in memory. Needs neither network
+    /// access nor privileges.
+    SelfTest,
+
+    /// Measure the speed of handshakes
+    ///
+    /// Runs handshakes between two keypairs entirely in memory and reports the
+    /// handshakes per second, their latency and the peak memory use. Like
+    /// self-test, this needs neither network access nor privileges.
+    Bench {
+        /// number of handshakes to run
+        #[clap(short = 'n', long, default_value_t = 100)]
+        iterations: u32,
+
+        /// print the results as a JSON object
+        #[clap(long)]
+        json: bool,
+    },
+
     /// Show the rosenpass manpage
     // TODO make this the default, but only after the manpage has been adjusted once the CLI stabilizes
     Man,
@@ -189,17 +366,17 @@ impl CliCommand {
                             public_key = Some(opt.into());
                         }
                         (Some(flag), _) => {
-                            bail!("Unknown option `{}`", flag);
+                            return Err(CliError::UnrecognisedArgument(flag.to_owned()).into());
                         }
                         (_, _) => break,
                     };
                 }
 
                 if secret_key.is_none() {
-                    bail!("private-key is required");
+                    return Err(CliError::MissingArgument("private-key").into());
                 }
                 if public_key.is_none() {
-                    bail!("public-key is required");
+                    return Err(CliError::MissingArgument("public-key").into());
                 }
 
                 generate_and_save_keypair(secret_key.unwrap(), public_key.unwrap())?;
@@ -210,6 +387,7 @@ impl CliCommand {
                 public_key,
                 secret_key,
                 force,
+                seed,
             } => {
                 // figure out where the key file is specified, in the config file or directly as flag?
                 let (pkf, skf) = match (config_file, public_key, secret_key) {
@@ -246,18 +424,64 @@ impl CliCommand {
                 }
 
                 // generate the keys and store them in files
-                generate_and_save_keypair(skf, pkf)?;
+                match seed {
+                    Some(seed) => {
+                        let seed = seeded_keygen::parse_seed(&seed)?;
+                        log::warn!(
+                            "INSECURE: deriving the keys from a seed; they are only as secret \
+                            as the seed, use this for tests and recovery only"
+                        );
+                        let (ssk, spk) = stacker::grow(KEYGEN_STACK_SIZE, || {
+                            seeded_keygen::keypair_from_seed(&seed)
+                        })?;
+                        ssk.store_secret(skf)?;
+                        spk.store_secret(pkf)?;
+                    }
+                    None => generate_and_save_keypair(skf, pkf)?,
+                }
             }
 
-            ExchangeConfig { config_file } => {
-                ensure!(
-                    config_file.exists(),
-                    "config file '{config_file:?}' does not exist"
-                );
+            ExchangeConfig { config_files } => {
+                let mut configs = Vec::with_capacity(config_files.len());
+                for config_file in config_files {
+                    ensure!(
+                        config_file.exists(),
+                        "config file '{config_file:?}' does not exist"
+                    );
 
-                let config = config::Rosenpass::load(config_file)?;
-                config.validate()?;
-                Self::event_loop(config)?;
+                    let config = config::Rosenpass::load(&config_file)?;
+                    config
+                        .validate()
+                        .with_context(|| format!("invalid config file {config_file:?}"))?;
+                    configs.push((config_file, config));
+                }
+
+                // The log is shared by all tunnels of the process
+                let mut log_files = configs.iter().filter_map(|(_, c)| c.log_file.as_deref());
+                if let Some(log_file) = log_files.next() {
+                    ensure!(
+                        log_files.all(|other| other == log_file),
+                        "the configuration files name different log files"
+                    );
+                    crate::log_file::open(log_file)?;
+                }
+
+                if configs.iter().any(|(_, c)| c.setup_only) {
+                    // Each setup returns right away, so there is nothing to run side by side
+                    ensure!(
+                        configs.iter().all(|(_, c)| c.setup_only),
+                        "either all or none of the configuration files must be setup-only"
+                    );
+                    for (config_file, config) in configs {
+                        Self::event_loop(config)
+                            .with_context(|| format!("setting up {config_file:?} failed"))?;
+                    }
+                } else if configs.len() == 1 {
+                    let (_, config) = configs.pop().unwrap();
+                    Self::event_loop(config)?;
+                } else {
+                    Self::run_tunnels(configs)?;
+                }
             }
 
             Exchange {
@@ -268,15 +492,59 @@ impl CliCommand {
                 rest_of_args.insert(0, first_arg);
                 let args = rest_of_args;
                 let mut config = config::Rosenpass::parse_args(args)?;
+                if config.peers_stdin {
+                    config.read_peers_jsonl(std::io::stdin().lock())?;
+                }
 
                 if let Some(p) = config_file {
                     config.store(&p)?;
                     config.config_file_path = p;
                 }
                 config.validate()?;
+                if let Some(log_file) = config.log_file.as_deref() {
+                    crate::log_file::open(log_file)?;
+                }
                 Self::event_loop(config)?;
             }
 
+            Convert { from, to, force } => key_layout::convert(&from, &to, force)?,
+
+            Info => print_info(),
+
+            #[cfg(unix)]
+            Status { socket, request } => {
+                use std::io::{Read, Write};
+                use std::os::unix::net::UnixStream;
+
+                let mut stream = UnixStream::connect(&socket)
+                    .with_context(|| format!("could not connect to control socket {socket:?}"))?;
+                writeln!(stream, "{request}")?;
+                let mut reply = String::new();
+                stream.read_to_string(&mut reply)?;
+                print!("{reply}");
+                ensure!(
+                    !reply.starts_with("error"),
+                    "the request {request:?} failed"
+                );
+            }
+
+            #[cfg(unix)]
+            AgentKey { socket, key } => {
+                use std::io::Write;
+
+                // The secret key is binary and never handed to other programs
+                ensure!(key == "wgsk", "unknown key {key:?}, expected \"wgsk\"");
+                let reply = AgentKeySource { socket }.request(&key)?;
+                std::io::stdout().write_all(&reply)?;
+            }
+
+            SelfTest => {
+                self_test()?;
+                println!("self-test passed");
+            }
+
+            Bench { iterations, json } => print_bench(&bench(iterations)?, json),
+
             Validate { config_files } => {
                 for file in config_files {
                     match config::Rosenpass::load(&file) {
@@ -296,43 +564,514 @@ impl CliCommand {
         Ok(())
     }
 
+    /// run the event loop of every config in a thread of its own, until one of them fails
+    fn run_tunnels(configs: Vec<(PathBuf, config::Rosenpass)>) -> anyhow::Result<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        for (config_file, config) in configs {
+            let tx = tx.clone();
+            std::thread::Builder::new()
+                .name(config_file.display().to_string())
+                .stack_size(TUNNEL_STACK_SIZE)
+                .spawn(move || {
+                    let res = Self::event_loop(config);
+                    // The receiver only goes away once the process exits
+                    let _ = tx.send((config_file, res));
+                })
+                .context("could not start a tunnel thread")?;
+        }
+        drop(tx);
+
+        match rx.recv() {
+            Ok((config_file, res)) => {
+                res.with_context(|| format!("tunnel {config_file:?} failed"))?;
+                bail!("tunnel {config_file:?} stopped")
+            }
+            Err(_) => bail!("all tunnels stopped"),
+        }
+    }
+
     fn event_loop(config: config::Rosenpass) -> anyhow::Result<()> {
+        let verbose = matches!(config.verbosity, Verbosity::Verbose);
+        if config.redact {
+            log_redact::enable();
+        }
+
         // load own keys
-        let sk = SSk::load(&config.secret_key)?;
-        let pk = SPk::load(&config.public_key)?;
+        let sk = match &config.agent_socket {
+            #[cfg(unix)]
+            Some(socket) => AgentKeySource {
+                socket: socket.clone(),
+            }
+            .secret_key()?,
+            #[cfg(not(unix))]
+            Some(_) => bail!("agent-socket needs unix domain sockets"),
+            None => FileKeySource {
+                path: config.secret_key.clone(),
+                verbose,
+            }
+            .secret_key()?,
+        };
+        let pk = load_key(
+            "public key",
+            &config.public_key,
+            verbose,
+            Some(StaticKem::PK_LEN),
+            SPk::load,
+        )?;
+
+        let timing = config.protocol_timing()?;
 
         // start an application server
         let mut srv = std::boxed::Box::<AppServer>::new(AppServer::new(
             sk,
             pk,
             config.listen,
+            config.port_range,
+            config.best_effort_listen,
             config.verbosity,
         )?);
+        srv.crypt.timing = timing;
+        if let Some(addr) = config.source_address {
+            srv.bind_source_address(addr)?;
+        }
+        if let Some(dev) = config.bind_device.as_deref() {
+            srv.bind_device(dev)?;
+        }
+        srv.set_socket_buffers(config.rcvbuf, config.sndbuf)?;
+        if let Some(path) = config.control_socket.as_deref() {
+            #[cfg(unix)]
+            srv.listen_control(path)?;
+            #[cfg(not(unix))]
+            bail!("control-socket {path:?} is only supported on unix");
+        }
+        if verbose {
+            for addr in srv.local_addrs()? {
+                log::info!("listening on {}", redacted(addr));
+            }
+        }
+
+        let results = setup_peers(&mut srv, config.peers, verbose);
+        let peer_count = results.len();
+        let mut skipped = Vec::new();
+        for res in results {
+            let PeerSetupResult {
+                index,
+                label,
+                outcome,
+                ..
+            } = res;
+            match outcome {
+                Ok(_) => {}
+                Err(e) if config.skip_bad_peers => {
+                    log::warn!("skipping peer {index} ({}): {e:#}", redacted(&label));
+                    skipped.push(index);
+                }
+                Err(e) => {
+                    return Err(
+                        e.context(format!("could not add peer {index} ({})", redacted(&label)))
+                    )
+                }
+            }
+        }
+
+        if !skipped.is_empty() {
+            ensure!(
+                skipped.len() < peer_count,
+                "none of the {peer_count} peers could be added"
+            );
+            log::warn!(
+                "skipped {} of {peer_count} peers: {skipped:?}",
+                skipped.len()
+            );
+        }
 
-        for cfg_peer in config.peers {
-            srv.add_peer(
-                // psk, pk, outfile, outwg, tx_addr
-                cfg_peer.pre_shared_key.map(SymKey::load_b64).transpose()?,
-                SPk::load(&cfg_peer.public_key)?,
-                cfg_peer.key_out,
-                cfg_peer.wg.map(|cfg| app_server::WireguardOut {
-                    dev: cfg.device,
-                    pk: cfg.peer,
-                    extra_params: cfg.extra_params,
-                }),
-                cfg_peer.endpoint.clone(),
-            )?;
+        if config.port_range.is_some() {
+            // All sockets share the port picked from the range
+            let port = srv.local_addrs()?[0].port();
+            srv.set_wireguard_listen_port(port + 1)?;
         }
 
+        if config.setup_only {
+            srv.setup_wireguard()?;
+            log::info!("peers are set up, exiting without exchanging keys (setup-only)");
+            return Ok(());
+        }
+
+        #[cfg(unix)]
+        app_server::install_rekey_signal()?;
+
         srv.event_loop()
     }
 }
 
+/// The outcome of adding one configured peer to an [AppServer]
+#[derive(Debug)]
+pub struct PeerSetupResult {
+    /// Position of the peer in the configuration
+    pub index: usize,
+    /// The peer's name, or the path of its public key if it has none
+    pub label: String,
+    /// The peer as added to the server, or why it could not be added
+    pub outcome: anyhow::Result<app_server::AppPeerPtr>,
+    /// The addresses the configured endpoint resolved to
+    pub endpoint: Vec<std::net::SocketAddr>,
+    /// The allowed-ips passed on to WireGuard for this peer
+    pub allowed_ips: Vec<String>,
+}
+
+/// Add the configured peers to `srv`, one [PeerSetupResult] per peer in configuration order
+///
+/// A peer that can not be added does not stop the others from being added; it is up to
+/// the caller to decide whether a partial setup is acceptable. With `verbose`, each peer is
+/// logged as soon as it was added, so a peer that stalls the setup can be told apart.
+pub fn setup_peers(
+    srv: &mut AppServer,
+    peers: Vec<config::RosenpassPeer>,
+    verbose: bool,
+) -> Vec<PeerSetupResult> {
+    let peer_count = peers.len();
+    peers
+        .into_iter()
+        .enumerate()
+        .map(|(index, cfg_peer)| {
+            let label = cfg_peer
+                .name
+                .clone()
+                .unwrap_or_else(|| cfg_peer.public_key.display().to_string());
+            let allowed_ips = cfg_peer
+                .wg
+                .as_ref()
+                .map(|wg| allowed_ips(&wg.extra_params))
+                .unwrap_or_default();
+            let started = std::time::Instant::now();
+            let add_peer = || -> anyhow::Result<app_server::AppPeerPtr> {
+                srv.add_peer(
+                    // psk, pk, outfile, outwg, tx_addr, name
+                    cfg_peer
+                        .pre_shared_key
+                        .map(|psk| load_key("peer psk", &psk, verbose, None, SymKey::load_b64))
+                        .transpose()?,
+                    load_key(
+                        "peer public key",
+                        &cfg_peer.public_key,
+                        verbose,
+                        Some(StaticKem::PK_LEN),
+                        SPk::load,
+                    )?,
+                    cfg_peer.key_out,
+                    cfg_peer.wg.map(|cfg| app_server::WireguardOut {
+                        dev: cfg.device,
+                        pk: cfg.peer,
+                        extra_params: cfg.extra_params,
+                    }),
+                    cfg_peer.endpoint,
+                    cfg_peer.name,
+                )
+            };
+            let outcome = add_peer();
+            let endpoint = match &outcome {
+                Ok(peer) => peer
+                    .get_app(srv)
+                    .initial_endpoint
+                    .as_ref()
+                    .map(|ep| ep.addresses().to_vec())
+                    .unwrap_or_default(),
+                Err(_) => Vec::new(),
+            };
+            if verbose && outcome.is_ok() {
+                log::info!(
+                    "added peer {}/{peer_count}: {} in {:.2?}, endpoint {}, allowed-ips {}",
+                    index + 1,
+                    redacted(&label),
+                    started.elapsed(),
+                    fmt_list(&endpoint),
+                    fmt_list(&allowed_ips),
+                );
+            }
+            PeerSetupResult {
+                index,
+                label,
+                outcome,
+                endpoint,
+                allowed_ips,
+            }
+        })
+        .collect()
+}
+
+/// the values given to `allowed-ips` among the extra parameters passed to `wg set`
+fn allowed_ips(extra_params: &[String]) -> Vec<String> {
+    extra_params
+        .windows(2)
+        .filter(|w| w[0] == "allowed-ips")
+        .flat_map(|w| w[1].split(','))
+        .map(|ip| ip.trim().to_owned())
+        .filter(|ip| !ip.is_empty())
+        .collect()
+}
+
+/// comma separated list for logging, of [redacted] items; `none` if empty
+fn fmt_list<T: std::fmt::Display>(items: &[T]) -> String {
+    if items.is_empty() {
+        return "none".to_owned();
+    }
+    log_redact::redacted_list(items)
+}
+
+/// load a key with `load`, naming the absolute path of the file in errors and,
+/// if `verbose`, in the log
+///
+/// A raw key file of `expected_len` bytes is checked for its size first, so a truncated
+/// or mismatched file is reported before any decoding work is done.
+pub(crate) fn load_key<T>(
+    what: &str,
+    path: &Path,
+    verbose: bool,
+    expected_len: Option<usize>,
+    load: impl FnOnce(PathBuf) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+    if verbose {
+        log::info!("loading {what} from {}", path.display());
+    }
+    // a missing file is left to `load` to report
+    if let (Some(expected), Ok(meta)) = (expected_len, std::fs::metadata(&path)) {
+        ensure!(
+            meta.len() == expected as u64,
+            "{what} file {} is {} bytes, expected {expected}",
+            path.display(),
+            meta.len()
+        );
+    }
+    load(path.clone()).with_context(|| format!("could not load {what} from {}", path.display()))
+}
+
+/// Stack needed to generate a keypair; the public key alone is about half a megabyte and
+/// the key generation of the static KEM uses several more
+const KEYGEN_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Stack of the thread running a tunnel, which loads keys and handles messages just like
+/// the main thread does with a single tunnel
+const TUNNEL_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// print the algorithms and key sizes this build uses
+fn print_info() {
+    use rosenpass_ciphers::kem::{EphemeralKem, EPHEMERAL_KEM_NAME, STATIC_KEM_NAME};
+    use rosenpass_ciphers::{aead, hash_domain, xaead};
+
+    fn kem<K: Kem>(what: &str, name: &str) {
+        println!(
+            "{what}: {name} (secret key {} bytes, public key {} bytes, \
+            ciphertext {} bytes, shared secret {} bytes)",
+            K::SK_LEN,
+            K::PK_LEN,
+            K::CT_LEN,
+            K::SHK_LEN
+        );
+    }
+
+    println!("rosenpass {}", env!("CARGO_PKG_VERSION"));
+    kem::<StaticKem>("static KEM", STATIC_KEM_NAME);
+    kem::<EphemeralKem>("ephemeral KEM", EPHEMERAL_KEM_NAME);
+    println!(
+        "AEAD: {} (key {} bytes, nonce {} bytes, tag {} bytes)",
+        aead::NAME,
+        aead::KEY_LEN,
+        aead::NONCE_LEN,
+        aead::TAG_LEN
+    );
+    println!(
+        "XAEAD: {} (key {} bytes, nonce {} bytes, tag {} bytes)",
+        xaead::NAME,
+        xaead::KEY_LEN,
+        xaead::NONCE_LEN,
+        xaead::TAG_LEN
+    );
+    println!(
+        "hash: {} (key {} bytes)",
+        hash_domain::NAME,
+        hash_domain::KEY_LEN
+    );
+    println!(
+        "keys: SSk {} bytes, SPk {} bytes, SymKey {} bytes",
+        StaticKem::SK_LEN,
+        StaticKem::PK_LEN,
+        rosenpass_ciphers::KEY_LEN
+    );
+    println!(
+        "build: {} {}, {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        }
+    );
+}
+
+/// generate a keypair in memory
+fn keygen() -> anyhow::Result<(SSk, SPk)> {
+    let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
+    StaticKem::keygen(sk.secret_mut(), pk.secret_mut()).context("key generation failed")?;
+    Ok((sk, pk))
+}
+
+/// two servers knowing each other as their only peer, sharing a preshared key
+fn server_pair(
+    (ska, pka): (SSk, SPk),
+    (skb, pkb): (SSk, SPk),
+) -> anyhow::Result<(CryptoServer, CryptoServer)> {
+    let psk = SymKey::random();
+    let mut a = CryptoServer::new(ska, pka.clone());
+    let mut b = CryptoServer::new(skb, pkb.clone());
+    a.add_peer(Some(psk.clone()), pkb)?;
+    b.add_peer(Some(psk), pka)?;
+    Ok((a, b))
+}
+
+/// run a handshake initiated by `a` in memory, failing if the servers do not agree on a key
+fn handshake<'a>(mut a: &'a mut CryptoServer, mut b: &'a mut CryptoServer) -> anyhow::Result<()> {
+    let (mut a_buf, mut b_buf) = (MsgBuf::zero(), MsgBuf::zero());
+    let mut maybe_len = Some(a.initiate_handshake(PeerPtr(0), a_buf.as_mut_slice())?);
+    while let Some(len) = maybe_len {
+        maybe_len = b
+            .handle_msg(&a_buf[..len], &mut b_buf[..])
+            .context("handshake failed")?
+            .resp;
+        std::mem::swap(&mut a, &mut b);
+        std::mem::swap(&mut a_buf, &mut b_buf);
+    }
+
+    ensure!(
+        a.osk(PeerPtr(0))?.secret() == b.osk(PeerPtr(0))?.secret(),
+        "the handshake did not establish a shared key"
+    );
+    Ok(())
+}
+
+/// generate two keypairs, round-trip one of them through files and run a handshake between
+/// them in memory, failing if they do not agree on a key
+fn self_test() -> anyhow::Result<()> {
+    stacker::grow(2 * KEYGEN_STACK_SIZE, || {
+        let (ska, pka) = keygen()?;
+        let b = keygen()?;
+
+        // key handling: the keys must survive being stored and loaded again
+        let dir = std::env::temp_dir().join(format!("rosenpass-self-test-{}", std::process::id()));
+        std::fs::create_dir(&dir).with_context(|| format!("could not create {dir:?}"))?;
+        let roundtrip = || -> anyhow::Result<(SSk, SPk)> {
+            ska.store_secret(dir.join("sk"))?;
+            pka.store_secret(dir.join("pk"))?;
+            Ok((SSk::load(dir.join("sk"))?, SPk::load(dir.join("pk"))?))
+        };
+        let loaded = roundtrip();
+        std::fs::remove_dir_all(&dir).with_context(|| format!("could not remove {dir:?}"))?;
+        let (ska, pka_loaded) = loaded.context("storing and loading keys failed")?;
+        ensure!(
+            pka_loaded.secret() == pka.secret(),
+            "the public key changed when storing and loading it"
+        );
+
+        let (mut a, mut b) = server_pair((ska, pka), b)?;
+        handshake(&mut a, &mut b)
+    })
+}
+
+/// results of [bench]
+struct BenchResult {
+    handshakes: u32,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+    /// peak resident memory of the process in KiB, where the platform tells
+    peak_rss_kib: Option<u64>,
+}
+
+/// run `iterations` handshakes in memory between two servers, timing each of them
+///
+/// Key generation happens once, up front, and is not part of the timings.
+fn bench(iterations: u32) -> anyhow::Result<BenchResult> {
+    ensure!(iterations > 0, "the number of iterations must be positive");
+    stacker::grow(2 * KEYGEN_STACK_SIZE, || {
+        let (mut a, mut b) = server_pair(keygen()?, keygen()?)?;
+        let (mut total, mut min, mut max) = (Duration::ZERO, Duration::MAX, Duration::ZERO);
+        for _ in 0..iterations {
+            let started = Instant::now();
+            handshake(&mut a, &mut b)?;
+            let elapsed = started.elapsed();
+            total += elapsed;
+            min = min.min(elapsed);
+            max = max.max(elapsed);
+        }
+        Ok(BenchResult {
+            handshakes: iterations,
+            total,
+            min,
+            max,
+            peak_rss_kib: peak_rss_kib(),
+        })
+    })
+}
+
+/// peak resident memory of this process in KiB
+#[cfg(target_os = "linux")]
+fn peak_rss_kib() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.trim_start_matches("VmHWM:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// peak resident memory of this process in KiB
+#[cfg(not(target_os = "linux"))]
+fn peak_rss_kib() -> Option<u64> {
+    None
+}
+
+fn print_bench(res: &BenchResult, json: bool) {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let avg = res.total / res.handshakes;
+    let per_second = res.handshakes as f64 / res.total.as_secs_f64();
+    if json {
+        let obj = serde_json::json!({
+            "handshakes": res.handshakes,
+            "seconds": res.total.as_secs_f64(),
+            "handshakes_per_second": per_second,
+            "latency_ms": { "avg": ms(avg), "min": ms(res.min), "max": ms(res.max) },
+            "peak_rss_kib": res.peak_rss_kib,
+        });
+        println!("{obj}");
+        return;
+    }
+    println!("handshakes: {}", res.handshakes);
+    println!("total: {:.3} s", res.total.as_secs_f64());
+    println!("handshakes per second: {per_second:.1}");
+    println!(
+        "latency: avg {:.3} ms, min {:.3} ms, max {:.3} ms",
+        ms(avg),
+        ms(res.min),
+        ms(res.max)
+    );
+    match res.peak_rss_kib {
+        Some(kib) => println!("peak memory: {kib} KiB"),
+        None => println!("peak memory: unknown"),
+    }
+}
+
 /// generate secret and public keys, store in files according to the paths passed as arguments
+///
+/// Runs on a stack of its own, so this works on threads with a small stack too
 fn generate_and_save_keypair(secret_key: PathBuf, public_key: PathBuf) -> anyhow::Result<()> {
-    let mut ssk = crate::protocol::SSk::random();
-    let mut spk = crate::protocol::SPk::random();
-    StaticKem::keygen(ssk.secret_mut(), spk.secret_mut())?;
-    ssk.store_secret(secret_key)?;
-    spk.store_secret(public_key)
+    stacker::grow(KEYGEN_STACK_SIZE, || {
+        let mut ssk = crate::protocol::SSk::random();
+        let mut spk = crate::protocol::SPk::random();
+        StaticKem::keygen(ssk.secret_mut(), spk.secret_mut())?;
+        ssk.store_secret(secret_key)?;
+        spk.store_secret(public_key)
+    })
 }
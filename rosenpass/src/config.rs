@@ -10,23 +10,45 @@
 use std::{
     collections::HashSet,
     fs,
-    io::Write,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    io::{BufRead, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
     path::{Path, PathBuf},
 };
 
-use anyhow::{bail, ensure};
+use anyhow::{bail, ensure, Context};
 use rosenpass_util::file::fopen_w;
 use serde::{Deserialize, Serialize};
 
+use crate::app_server::HostPathDiscoveryEndpoint;
+use crate::cli::{interpolate_env, CliError};
+use crate::protocol::{ProtocolTiming, Timing};
+
+/// the default limit on the number of peers, see [`Rosenpass::max_peers`]
+pub const DEFAULT_MAX_PEERS: usize = 1024;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Rosenpass {
+    /// version of rosenpass the configuration was written for
+    ///
+    /// Checked when loading, see [`Rosenpass::from_toml`]; configurations written by
+    /// rosenpass carry its own version.
+    #[serde(default)]
+    pub version: Option<String>,
+
     /// path to the public key file
     pub public_key: PathBuf,
 
     /// path to the secret key file
+    ///
+    /// Not needed if the key comes from an [`Self::agent_socket`].
+    #[serde(default)]
     pub secret_key: PathBuf,
 
+    /// Unix domain socket of a key agent the secret key is requested from instead of
+    /// reading [`Self::secret_key`], see [`crate::key_source`]
+    #[serde(default)]
+    pub agent_socket: Option<PathBuf>,
+
     /// list of [`SocketAddr`] to listen on
     ///
     /// Examples:
@@ -39,6 +61,91 @@ pub struct Rosenpass {
     #[serde(default)]
     pub verbosity: Verbosity,
 
+    /// seconds after which a session is rekeyed
+    ///
+    /// Defaults to the protocol's standard interval, see [`ProtocolTiming`].
+    #[serde(default)]
+    pub rekey_interval: Option<Timing>,
+
+    /// seconds after which an unfinished handshake is abandoned
+    ///
+    /// Defaults to the protocol's standard timeout, see [`ProtocolTiming`].
+    #[serde(default)]
+    pub handshake_timeout: Option<Timing>,
+
+    /// whether peers that fail to validate or load are skipped with a warning
+    ///
+    /// Setup only fails if none of the peers could be added.
+    #[serde(default)]
+    pub skip_bad_peers: bool,
+
+    /// whether listen addresses that can not be bound are skipped with a warning
+    ///
+    /// Setup only fails if none of them could be bound.
+    #[serde(default)]
+    pub best_effort_listen: bool,
+
+    /// whether to exit once the peers are configured in WireGuard, without exchanging keys
+    ///
+    /// WireGuard then runs with its static keys only; nothing rekeys it.
+    #[serde(default)]
+    pub setup_only: bool,
+
+    /// whether endpoints and peer identities are replaced by a short hash in the log, see
+    /// [`crate::log_redact`]
+    #[serde(default)]
+    pub redact: bool,
+
+    /// network interface the sockets are bound to using `SO_BINDTODEVICE`
+    ///
+    /// Only supported on Linux; elsewhere this is ignored with a warning.
+    #[serde(default)]
+    pub bind_device: Option<String>,
+
+    /// size of the receive buffer of the sockets in bytes (`SO_RCVBUF`)
+    ///
+    /// NOTE: the system may clamp this; the system default is used if this is not set
+    #[serde(default)]
+    pub rcvbuf: Option<usize>,
+
+    /// size of the send buffer of the sockets in bytes (`SO_SNDBUF`)
+    ///
+    /// NOTE: the system may clamp this; the system default is used if this is not set
+    #[serde(default)]
+    pub sndbuf: Option<usize>,
+
+    /// path of a Unix domain socket reporting the state of the daemon, see
+    /// [`crate::app_server::AppServer::listen_control`]
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+
+    /// local address handshake initiations are sent from, see
+    /// [`crate::app_server::AppServer::bind_source_address`]
+    #[serde(default)]
+    pub source_address: Option<IpAddr>,
+
+    /// file the log is appended to instead of stderr, see [`crate::log_file`]
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+
+    /// ports tried in turn for the [`Self::listen`] addresses, whose own ports are ignored
+    ///
+    /// The first port free on all of them is used, provided the port above it is free as
+    /// well; the WireGuard devices of the peers are set to listen on that one. See
+    /// [`crate::app_server::AppServer::new`].
+    #[serde(default)]
+    pub port_range: Option<(u16, u16)>,
+
+    /// the most peers a configuration may have, guarding against runaway generated configs
+    ///
+    /// Defaults to [`DEFAULT_MAX_PEERS`].
+    #[serde(default)]
+    pub max_peers: Option<usize>,
+
+    /// whether further peers are read from stdin, see [`Rosenpass::read_peers_jsonl`]
+    #[serde(skip)]
+    pub peers_stdin: bool,
+
     /// list of peers
     ///
     /// See the [`RosenpassPeer`] type for more information and examples.
@@ -68,6 +175,12 @@ pub struct RosenpassPeer {
     /// path to the public key of the peer
     pub public_key: PathBuf,
 
+    /// label identifying the peer in logs
+    ///
+    /// NOTE: if this is not set, the peer is identified by its peer id
+    #[serde(default)]
+    pub name: Option<String>,
+
     /// ## TODO
     /// - documentation
     pub endpoint: Option<String>,
@@ -118,7 +231,8 @@ impl Rosenpass {
     ///   behave unexpectedly on Windows
     pub fn load<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
         // read file and deserialize
-        let mut config: Self = toml::from_str(&fs::read_to_string(&p)?)?;
+        let mut config = Self::from_toml(&fs::read_to_string(&p)?)
+            .with_context(|| format!("could not load config file {:?}", p.as_ref()))?;
 
         // resolve `~` (see https://github.com/rosenpass/rosenpass/issues/237)
         use util::resolve_path_with_tilde;
@@ -132,6 +246,14 @@ impl Rosenpass {
             if let Some(ref mut ko) = &mut peer.key_out {
                 resolve_path_with_tilde(ko);
             }
+
+            // expand `${VAR}` like on the command line
+            if let Some(endpoint) = &mut peer.endpoint {
+                *endpoint = interpolate_env(endpoint)?;
+            }
+            for param in peer.wg.iter_mut().flat_map(|wg| wg.extra_params.iter_mut()) {
+                *param = interpolate_env(param)?;
+            }
         }
 
         // add path to "self"
@@ -141,6 +263,57 @@ impl Rosenpass {
         Ok(config)
     }
 
+    /// Deserialize a configuration, checking it against the version it was written for
+    ///
+    /// Options this version does not know are ignored; they are listed in a warning, or in
+    /// an error if the configuration was written for a newer version that is incompatible
+    /// by the rules of semantic versioning.
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let raw: toml::Value = toml::from_str(text)?;
+        let config: Self = raw.clone().try_into()?;
+
+        // whatever does not survive a round trip was not understood
+        let mut unknown = Vec::new();
+        unknown_keys(&raw, &toml::Value::try_from(&config)?, "", &mut unknown);
+        let unknown = if unknown.is_empty() {
+            String::new()
+        } else {
+            format!("; these options are not understood: {}", unknown.join(", "))
+        };
+
+        let ours = env!("CARGO_PKG_VERSION");
+        let our_version = parse_version(ours).expect("the crate version is valid");
+        let Some(theirs) = config.version.as_deref() else {
+            if !unknown.is_empty() {
+                log::warn!("configuration has options unknown to rosenpass {ours}{unknown}");
+            }
+            return Ok(config);
+        };
+        let their_version = parse_version(theirs)
+            .with_context(|| format!("version {theirs:?} is not a version number"))?;
+
+        let compatible = semver_compatible(their_version, our_version);
+        if their_version > our_version && !compatible {
+            bail!(
+                "configuration is for rosenpass {theirs}, which is incompatible with \
+                this version {ours}{unknown}"
+            );
+        } else if their_version > our_version {
+            log::warn!(
+                "configuration is for rosenpass {theirs}, newer than this version {ours}{unknown}"
+            );
+        } else if !compatible {
+            log::warn!(
+                "configuration is for rosenpass {theirs}, options may behave differently in \
+                this version {ours}{unknown}"
+            );
+        } else if !unknown.is_empty() {
+            log::warn!("configuration has options unknown to rosenpass {ours}{unknown}");
+        }
+
+        Ok(config)
+    }
+
     /// Write a config to a file
     pub fn store<P: AsRef<Path>>(&self, p: P) -> anyhow::Result<()> {
         let serialized_config =
@@ -163,6 +336,14 @@ impl Rosenpass {
     /// - check that files do not just exist but are also readable
     /// - warn if neither out_key nor exchange_command of a peer is defined (v.i.)
     pub fn validate(&self) -> anyhow::Result<()> {
+        // check the number of peers first, before looking at any of them
+        let max_peers = self.max_peers.unwrap_or(DEFAULT_MAX_PEERS);
+        ensure!(
+            self.peers.len() <= max_peers,
+            "{} peers configured, more than max-peers {max_peers}; raise max-peers if this is intended",
+            self.peers.len()
+        );
+
         // check the public key file exists
         ensure!(
             self.public_key.is_file(),
@@ -170,48 +351,133 @@ impl Rosenpass {
             self.public_key
         );
 
-        // check the secret-key file exists
+        // check the secret-key file exists, unless a key agent hands out the key
         ensure!(
-            self.secret_key.is_file(),
+            self.agent_socket.is_some() || self.secret_key.is_file(),
             "could not find secret-key file {:?}: no such file",
             self.secret_key
         );
 
         for (i, peer) in self.peers.iter().enumerate() {
-            // check peer's public-key file exists
+            match Self::validate_peer(i, peer) {
+                Err(e) if self.skip_bad_peers => log::warn!("{e}, skipping this peer"),
+                res => res?,
+            }
+        }
+
+        if let Some((lo, hi)) = self.port_range {
+            ensure!(
+                lo > 0 && lo <= hi,
+                "port-range {lo}-{hi} must start at port 1 or above and not end before it starts"
+            );
+            ensure!(
+                hi < u16::MAX,
+                "port-range {lo}-{hi} leaves no port for WireGuard, which listens on the port + 1"
+            );
+            ensure!(
+                !self.listen.is_empty(),
+                "port-range requires a listen address, e.g. listen 0.0.0.0"
+            );
             ensure!(
-                peer.public_key.is_file(),
-                "peer {i} public-key file {:?} does not exist",
-                peer.public_key
+                self.listen.iter().all(|addr| addr.port() == 0),
+                "port-range picks the port, the listen addresses can not have ports of their own"
             );
+            ensure!(
+                !self.best_effort_listen,
+                "port-range can not be combined with best-effort-listen"
+            );
+        }
 
-            // check endpoint is usable
-            if let Some(addr) = peer.endpoint.as_ref() {
-                ensure!(
-                    addr.to_socket_addrs().is_ok(),
-                    "peer {i} endpoint {} can not be parsed to a socket address",
-                    addr
-                );
-            }
+        // nothing would answer on the socket, but its file would stay behind
+        ensure!(
+            !self.setup_only || self.control_socket.is_none(),
+            "setup-only exits right away, it can not be combined with a control-socket"
+        );
+
+        // check the timings are within bounds
+        self.protocol_timing()?;
 
-            // TODO warn if neither out_key nor exchange_command is defined
+        Ok(())
+    }
+
+    fn validate_peer(i: usize, peer: &RosenpassPeer) -> anyhow::Result<()> {
+        // check peer's public-key file exists
+        ensure!(
+            peer.public_key.is_file(),
+            "peer {i} public-key file {:?} does not exist",
+            peer.public_key
+        );
+
+        // check endpoint is usable, the same way it is resolved later on
+        if let Some(addr) = peer.endpoint.as_ref() {
+            HostPathDiscoveryEndpoint::lookup(addr.clone()).with_context(|| {
+                format!("peer {i} endpoint {addr} can not be parsed to a socket address")
+            })?;
         }
 
+        // TODO warn if neither out_key nor exchange_command is defined
+
         Ok(())
     }
 
+    /// The protocol timing resulting from the configured rekey interval and handshake timeout
+    pub fn protocol_timing(&self) -> anyhow::Result<ProtocolTiming> {
+        let mut timing = ProtocolTiming::default();
+        if let Some(interval) = self.rekey_interval {
+            timing = timing.with_rekey_interval(interval)?;
+        }
+        if let Some(timeout) = self.handshake_timeout {
+            timing = timing.with_handshake_timeout(timeout)?;
+        }
+        Ok(timing)
+    }
+
     /// Creates a new configuration
     pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(public_key: P1, secret_key: P2) -> Self {
         Self {
+            version: Some(env!("CARGO_PKG_VERSION").to_owned()),
             public_key: PathBuf::from(public_key.as_ref()),
             secret_key: PathBuf::from(secret_key.as_ref()),
             listen: vec![],
             verbosity: Verbosity::Quiet,
+            rekey_interval: None,
+            handshake_timeout: None,
+            skip_bad_peers: false,
+            best_effort_listen: false,
+            setup_only: false,
+            redact: false,
+            bind_device: None,
+            rcvbuf: None,
+            sndbuf: None,
+            control_socket: None,
+            source_address: None,
+            log_file: None,
+            agent_socket: None,
+            max_peers: None,
+            port_range: None,
+            peers_stdin: false,
             peers: vec![],
             config_file_path: PathBuf::new(),
         }
     }
 
+    /// Add the peers read from `reader`, which holds one JSON object per line
+    ///
+    /// Each object has the same fields as a peer in the TOML configuration
+    /// (see [`RosenpassPeer`]); empty lines are ignored.
+    pub fn read_peers_jsonl<R: BufRead>(&mut self, reader: R) -> anyhow::Result<()> {
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let peer = serde_json::from_str(&line)
+                .map_err(|e| anyhow::anyhow!("invalid peer on line {} of stdin: {e}", i + 1))?;
+            self.peers.push(peer);
+        }
+        Ok(())
+    }
+
     /// Add IPv4 __and__ IPv6 IF_ANY address to the listen interfaces
     pub fn add_if_any(&mut self, port: u16) {
         let ipv4_any = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port));
@@ -227,7 +493,7 @@ impl Rosenpass {
 
     /// from chaotic args
     /// Quest: the grammar is undecideable, what do we do here?
-    pub fn parse_args(args: Vec<String>) -> anyhow::Result<Self> {
+    pub fn parse_args(args: Vec<String>) -> Result<Self, CliError> {
         let mut config = Self::new("", "");
 
         #[derive(Debug, Hash, PartialEq, Eq)]
@@ -236,9 +502,21 @@ impl Rosenpass {
             OwnPublicKey,
             OwnSecretKey,
             OwnListen,
+            OwnRekeyInterval,
+            OwnHandshakeTimeout,
+            OwnBindDevice,
+            OwnRcvbuf,
+            OwnSndbuf,
+            OwnControlSocket,
+            OwnSourceAddress,
+            OwnLogFile,
+            OwnAgentSocket,
+            OwnMaxPeers,
+            OwnPortRange,
             Peer,
             PeerPsk,
             PeerPublicKey,
+            PeerName,
             PeerEndpoint,
             PeerOutfile,
             PeerWireguardDev,
@@ -265,19 +543,50 @@ impl Rosenpass {
                     OwnSecretKey
                 }
                 (Own, "listen", None) => OwnListen,
+                (Own, "rekey-interval", None) => OwnRekeyInterval,
+                (Own, "handshake-timeout", None) => OwnHandshakeTimeout,
+                (Own, "bind-device", None) => OwnBindDevice,
+                (Own, "rcvbuf", None) => OwnRcvbuf,
+                (Own, "sndbuf", None) => OwnSndbuf,
+                (Own, "control-socket", None) => OwnControlSocket,
+                (Own, "source-address", None) => OwnSourceAddress,
+                (Own, "log-file", None) => OwnLogFile,
+                (Own, "agent-socket", None) => OwnAgentSocket,
+                (Own, "max-peers", None) => OwnMaxPeers,
+                (Own, "port-range", None) => OwnPortRange,
                 (Own, "verbose", None) => {
                     config.verbosity = Verbosity::Verbose;
                     Own
                 }
+                (Own, "skip-bad-peers", None) => {
+                    config.skip_bad_peers = true;
+                    Own
+                }
+                (Own, "setup-only", None) => {
+                    config.setup_only = true;
+                    Own
+                }
+                (Own, "redact", None) => {
+                    config.redact = true;
+                    Own
+                }
+                (Own, "best-effort-listen", None) => {
+                    config.best_effort_listen = true;
+                    Own
+                }
+                (Own, "peers-stdin", None) => {
+                    config.peers_stdin = true;
+                    Own
+                }
                 (Own, "peer", None) => {
-                    ensure!(
-                        already_set.contains(&OwnPublicKey),
-                        "public-key file must be set"
-                    );
-                    ensure!(
-                        already_set.contains(&OwnSecretKey),
-                        "secret-key file must be set"
-                    );
+                    if !already_set.contains(&OwnPublicKey) {
+                        return Err(CliError::MissingArgument("public-key file"));
+                    }
+                    if !already_set.contains(&OwnSecretKey)
+                        && !already_set.contains(&OwnAgentSocket)
+                    {
+                        return Err(CliError::MissingArgument("secret-key file"));
+                    }
 
                     already_set.clear();
                     current_peer = Some(RosenpassPeer::default());
@@ -285,29 +594,146 @@ impl Rosenpass {
                     Peer
                 }
                 (OwnPublicKey, pk, None) => {
-                    ensure!(
-                        already_set.insert(OwnPublicKey),
-                        "public-key was already set"
-                    );
+                    if !already_set.insert(OwnPublicKey) {
+                        return Err(CliError::AlreadySet("public-key"));
+                    }
                     config.public_key = pk.into();
                     Own
                 }
                 (OwnSecretKey, sk, None) => {
-                    ensure!(
-                        already_set.insert(OwnSecretKey),
-                        "secret-key was already set"
-                    );
+                    if !already_set.insert(OwnSecretKey) {
+                        return Err(CliError::AlreadySet("secret-key"));
+                    }
                     config.secret_key = sk.into();
                     Own
                 }
                 (OwnListen, l, None) => {
                     already_set.insert(OwnListen); // multiple listen directives are allowed
-                    for socket_addr in l.to_socket_addrs()? {
-                        config.listen.push(socket_addr);
+
+                    // an address without port gets one from the system or the port-range
+                    if let Ok(ip) = l.parse::<IpAddr>() {
+                        config.listen.push(SocketAddr::new(ip, 0));
+                    } else {
+                        let socket_addrs =
+                            l.to_socket_addrs()
+                                .map_err(|source| CliError::InvalidSocketAddr {
+                                    addr: l.to_owned(),
+                                    source,
+                                })?;
+                        config.listen.extend(socket_addrs);
                     }
 
                     Own
                 }
+                (OwnRekeyInterval, i, None) => {
+                    if !already_set.insert(OwnRekeyInterval) {
+                        return Err(CliError::AlreadySet("rekey-interval"));
+                    }
+                    config.rekey_interval =
+                        Some(i.parse().map_err(|source| CliError::InvalidNumber {
+                            option: "rekey-interval",
+                            value: i.to_owned(),
+                            source,
+                        })?);
+                    Own
+                }
+                (OwnHandshakeTimeout, t, None) => {
+                    if !already_set.insert(OwnHandshakeTimeout) {
+                        return Err(CliError::AlreadySet("handshake-timeout"));
+                    }
+                    config.handshake_timeout =
+                        Some(t.parse().map_err(|source| CliError::InvalidNumber {
+                            option: "handshake-timeout",
+                            value: t.to_owned(),
+                            source,
+                        })?);
+                    Own
+                }
+                (OwnBindDevice, dev, None) => {
+                    if !already_set.insert(OwnBindDevice) {
+                        return Err(CliError::AlreadySet("bind-device"));
+                    }
+                    config.bind_device = Some(dev.to_owned());
+                    Own
+                }
+                (OwnRcvbuf, size, None) => {
+                    if !already_set.insert(OwnRcvbuf) {
+                        return Err(CliError::AlreadySet("rcvbuf"));
+                    }
+                    config.rcvbuf = Some(size.parse().map_err(|source| CliError::InvalidSize {
+                        option: "rcvbuf",
+                        value: size.to_owned(),
+                        source,
+                    })?);
+                    Own
+                }
+                (OwnSndbuf, size, None) => {
+                    if !already_set.insert(OwnSndbuf) {
+                        return Err(CliError::AlreadySet("sndbuf"));
+                    }
+                    config.sndbuf = Some(size.parse().map_err(|source| CliError::InvalidSize {
+                        option: "sndbuf",
+                        value: size.to_owned(),
+                        source,
+                    })?);
+                    Own
+                }
+                (OwnControlSocket, path, None) => {
+                    if !already_set.insert(OwnControlSocket) {
+                        return Err(CliError::AlreadySet("control-socket"));
+                    }
+                    config.control_socket = Some(path.into());
+                    Own
+                }
+                (OwnSourceAddress, addr, None) => {
+                    if !already_set.insert(OwnSourceAddress) {
+                        return Err(CliError::AlreadySet("source-address"));
+                    }
+                    config.source_address =
+                        Some(addr.parse().map_err(|source| CliError::InvalidIpAddr {
+                            addr: addr.to_owned(),
+                            source,
+                        })?);
+                    Own
+                }
+                (OwnLogFile, path, None) => {
+                    if !already_set.insert(OwnLogFile) {
+                        return Err(CliError::AlreadySet("log-file"));
+                    }
+                    config.log_file = Some(path.into());
+                    Own
+                }
+                (OwnAgentSocket, path, None) => {
+                    if !already_set.insert(OwnAgentSocket) {
+                        return Err(CliError::AlreadySet("agent-socket"));
+                    }
+                    config.agent_socket = Some(path.into());
+                    Own
+                }
+                (OwnMaxPeers, n, None) => {
+                    if !already_set.insert(OwnMaxPeers) {
+                        return Err(CliError::AlreadySet("max-peers"));
+                    }
+                    config.max_peers =
+                        Some(n.parse().map_err(|source| CliError::InvalidCount {
+                            option: "max-peers",
+                            value: n.to_owned(),
+                            source,
+                        })?);
+                    Own
+                }
+                (OwnPortRange, range, None) => {
+                    if !already_set.insert(OwnPortRange) {
+                        return Err(CliError::AlreadySet("port-range"));
+                    }
+                    let invalid = || CliError::InvalidPortRange(range.to_owned());
+                    let (lo, hi) = range.split_once('-').ok_or_else(invalid)?;
+                    config.port_range = Some((
+                        lo.parse().map_err(|_| invalid())?,
+                        hi.parse().map_err(|_| invalid())?,
+                    ));
+                    Own
+                }
                 (Peer | PeerWireguardExtraArgs, "peer", maybe_peer @ Some(_)) => {
                     // TODO check current peer
                     // commit current peer, create a new one
@@ -319,41 +745,50 @@ impl Rosenpass {
                     Peer
                 }
                 (Peer, "public-key", Some(_)) => PeerPublicKey,
+                (Peer, "name", Some(_)) => PeerName,
                 (Peer, "endpoint", Some(_)) => PeerEndpoint,
                 (Peer, "preshared-key", Some(_)) => PeerPsk,
                 (Peer, "outfile", Some(_)) => PeerOutfile,
                 (Peer, "wireguard", Some(_)) => PeerWireguardDev,
                 (PeerPublicKey, pk, Some(peer)) => {
-                    ensure!(
-                        already_set.insert(PeerPublicKey),
-                        "public-key was already set"
-                    );
+                    if !already_set.insert(PeerPublicKey) {
+                        return Err(CliError::AlreadySet("public-key"));
+                    }
                     peer.public_key = pk.into();
                     Peer
                 }
+                (PeerName, name, Some(peer)) => {
+                    if !already_set.insert(PeerName) {
+                        return Err(CliError::AlreadySet("peer name"));
+                    }
+                    peer.name = Some(name.to_owned());
+                    Peer
+                }
                 (PeerEndpoint, e, Some(peer)) => {
-                    ensure!(already_set.insert(PeerEndpoint), "endpoint was already set");
-                    peer.endpoint = Some(e.to_owned());
+                    if !already_set.insert(PeerEndpoint) {
+                        return Err(CliError::AlreadySet("endpoint"));
+                    }
+                    peer.endpoint = Some(interpolate_env(e)?);
                     Peer
                 }
                 (PeerPsk, psk, Some(peer)) => {
-                    ensure!(already_set.insert(PeerEndpoint), "peer psk was already set");
+                    if !already_set.insert(PeerPsk) {
+                        return Err(CliError::AlreadySet("peer psk"));
+                    }
                     peer.pre_shared_key = Some(psk.into());
                     Peer
                 }
                 (PeerOutfile, of, Some(peer)) => {
-                    ensure!(
-                        already_set.insert(PeerOutfile),
-                        "peer outfile was already set"
-                    );
+                    if !already_set.insert(PeerOutfile) {
+                        return Err(CliError::AlreadySet("peer outfile"));
+                    }
                     peer.key_out = Some(of.into());
                     Peer
                 }
                 (PeerWireguardDev, dev, Some(peer)) => {
-                    ensure!(
-                        already_set.insert(PeerWireguardDev),
-                        "peer wireguard-dev was already set"
-                    );
+                    if !already_set.insert(PeerWireguardDev) {
+                        return Err(CliError::AlreadySet("peer wireguard-dev"));
+                    }
                     assert!(peer.wg.is_none());
                     peer.wg = Some(WireGuard {
                         device: dev.to_string(),
@@ -363,10 +798,9 @@ impl Rosenpass {
                     PeerWireguardPeer
                 }
                 (PeerWireguardPeer, p, Some(peer)) => {
-                    ensure!(
-                        already_set.insert(PeerWireguardPeer),
-                        "peer wireguard-peer was already set"
-                    );
+                    if !already_set.insert(PeerWireguardPeer) {
+                        return Err(CliError::AlreadySet("peer wireguard-peer"));
+                    }
                     peer.wg.as_mut().expect(wg_exists).peer = p.to_string();
                     PeerWireguardExtraArgs
                 }
@@ -375,26 +809,34 @@ impl Rosenpass {
                         .as_mut()
                         .expect(wg_exists)
                         .extra_params
-                        .push(arg.to_string());
+                        .push(interpolate_env(arg)?);
                     PeerWireguardExtraArgs
                 }
 
                 // error cases
                 (Own, x, None) => {
-                    bail!("unrecognised argument {x}");
+                    return Err(CliError::UnrecognisedArgument(x.to_owned()));
                 }
-                (Own | OwnPublicKey | OwnSecretKey | OwnListen, _, Some(_)) => {
+                (
+                    Own | OwnPublicKey | OwnSecretKey | OwnListen | OwnRekeyInterval
+                    | OwnHandshakeTimeout | OwnBindDevice | OwnRcvbuf | OwnSndbuf
+                    | OwnControlSocket | OwnSourceAddress | OwnLogFile | OwnAgentSocket
+                    | OwnMaxPeers | OwnPortRange,
+                    _,
+                    Some(_),
+                ) => {
                     panic!("current_peer is not None while in Own* state, this must never happen")
                 }
 
                 (State::Peer, arg, Some(_)) => {
-                    bail!("unrecongnised argument {arg}");
+                    return Err(CliError::UnrecognisedArgument(arg.to_owned()));
                 }
                 (
                     Peer
                     | PeerEndpoint
                     | PeerOutfile
                     | PeerPublicKey
+                    | PeerName
                     | PeerPsk
                     | PeerWireguardDev
                     | PeerWireguardPeer
@@ -421,6 +863,7 @@ impl Rosenpass {
     pub fn example_config() -> Self {
         let peer = RosenpassPeer {
             public_key: "/path/to/rp-peer-public-key".into(),
+            name: Some("my-peer".into()),
             endpoint: Some("my-peer.test:9999".into()),
             key_out: Some("/path/to/rp-key-out.txt".into()),
             pre_shared_key: Some("additional pre shared key".into()),
@@ -440,6 +883,47 @@ impl Rosenpass {
     }
 }
 
+/// collect the paths of the keys in `raw` that are missing from `known`, such as
+/// `peers[0].keepalive`
+fn unknown_keys(raw: &toml::Value, known: &toml::Value, path: &str, out: &mut Vec<String>) {
+    match (raw, known) {
+        (toml::Value::Table(raw), toml::Value::Table(known)) => {
+            for (key, value) in raw {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                match known.get(key) {
+                    Some(known) => unknown_keys(value, known, &path, out),
+                    None => out.push(path),
+                }
+            }
+        }
+        (toml::Value::Array(raw), toml::Value::Array(known)) => {
+            for (i, (raw, known)) in raw.iter().zip(known).enumerate() {
+                unknown_keys(raw, known, &format!("{path}[{i}]"), out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `(major, minor, patch)` of a version such as `0.2.1`, ignoring pre-release and build
+/// suffixes
+fn parse_version(v: &str) -> Option<(u64, u64, u64)> {
+    let core = v.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|p| p.parse().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(version)
+}
+
+/// whether two versions are compatible by the rules of semantic versioning, under which
+/// each `0.x` minor version may break compatibility
+fn semver_compatible(a: (u64, u64, u64), b: (u64, u64, u64)) -> bool {
+    a.0 == b.0 && (a.0 != 0 || a.1 == b.1)
+}
+
 impl Default for Verbosity {
     fn default() -> Self {
         Self::Quiet
@@ -485,6 +969,444 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_cli_parse_timing() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key \
+                rekey-interval 60 handshake-timeout 90.5 peer public-key /peer/public-key",
+        );
+
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.rekey_interval, Some(60.0));
+        assert_eq!(config.handshake_timeout, Some(90.5));
+
+        let timing = config.protocol_timing().unwrap();
+        assert_eq!(timing.rekey_after_time_responder, 60.0);
+        assert_eq!(timing.rekey_after_time_initiator, 70.0);
+        assert_eq!(timing.handshake_timeout, 90.5);
+
+        // pathological values are rejected
+        for args in [
+            "rekey-interval 0",
+            "rekey-interval 175",
+            "handshake-timeout 0.1",
+            "handshake-timeout 3600",
+        ] {
+            let args = split_str(&format!(
+                "public-key /my/public-key secret-key /my/secret-key {args}"
+            ));
+            let config = Rosenpass::parse_args(args).unwrap();
+            assert!(config.protocol_timing().is_err());
+        }
+
+        let args =
+            split_str("public-key /my/public-key secret-key /my/secret-key rekey-interval x");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::InvalidNumber {
+                option: "rekey-interval",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_errors() {
+        let args = split_str("public-key /my/public-key secret-key /my/secret-key bogus");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::UnrecognisedArgument(arg)) if arg == "bogus"
+        ));
+
+        let args = split_str("public-key /my/public-key peer public-key /peer/public-key");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::MissingArgument("secret-key file"))
+        ));
+
+        let args = split_str("public-key /a public-key /b secret-key /my/secret-key");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::AlreadySet("public-key"))
+        ));
+
+        let args = split_str("public-key /a secret-key /b listen not-an-address");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::InvalidSocketAddr { addr, .. }) if addr == "not-an-address"
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_skip_bad_peers() {
+        let args =
+            split_str("public-key /my/public-key secret-key /my/secret-key peer public-key /p");
+        assert!(!Rosenpass::parse_args(args).unwrap().skip_bad_peers);
+
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key skip-bad-peers \
+                peer public-key /p",
+        );
+        assert!(Rosenpass::parse_args(args).unwrap().skip_bad_peers);
+    }
+
+    #[test]
+    fn test_cli_parse_redact() {
+        let args =
+            split_str("public-key /my/public-key secret-key /my/secret-key peer public-key /p");
+        assert!(!Rosenpass::parse_args(args).unwrap().redact);
+
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key verbose redact \
+                peer public-key /p",
+        );
+        assert!(Rosenpass::parse_args(args).unwrap().redact);
+    }
+
+    #[test]
+    fn test_cli_parse_setup_only() {
+        let args =
+            split_str("public-key /my/public-key secret-key /my/secret-key peer public-key /p");
+        assert!(!Rosenpass::parse_args(args).unwrap().setup_only);
+
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key setup-only \
+                peer public-key /p",
+        );
+        assert!(Rosenpass::parse_args(args).unwrap().setup_only);
+    }
+
+    #[test]
+    fn test_cli_parse_port_range() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key listen 0.0.0.0 \
+                port-range 51000-51100 peer public-key /p",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.port_range, Some((51000, 51100)));
+        assert_eq!(config.listen, vec!["0.0.0.0:0".parse().unwrap()]);
+
+        for range in ["51000", "51000-", "a-b", "51000-70000"] {
+            let args = split_str(&format!(
+                "public-key /my/public-key secret-key /my/secret-key port-range {range}"
+            ));
+            assert!(matches!(
+                Rosenpass::parse_args(args),
+                Err(CliError::InvalidPortRange(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_port_range() {
+        let tmpdir = std::env::temp_dir().join("rp-test-validate-port-range");
+        fs::create_dir_all(&tmpdir).unwrap();
+        let (pk, sk) = (tmpdir.join("pk"), tmpdir.join("sk"));
+        fs::write(&pk, "").unwrap();
+        fs::write(&sk, "").unwrap();
+
+        let mut config = Rosenpass::new(&pk, &sk);
+        config.listen = vec!["0.0.0.0:0".parse().unwrap()];
+        for (range, ok) in [
+            ((51000, 51100), true),
+            ((51000, 51000), true),
+            ((51100, 51000), false),
+            ((0, 10), false),
+            ((65000, 65535), false),
+        ] {
+            config.port_range = Some(range);
+            assert_eq!(config.validate().is_ok(), ok, "{range:?}");
+        }
+
+        config.port_range = Some((51000, 51100));
+        config.listen = vec!["0.0.0.0:9999".parse().unwrap()];
+        assert!(config.validate().is_err());
+        config.listen = vec![];
+        assert!(config.validate().is_err());
+
+        fs::remove_dir_all(&tmpdir).unwrap();
+    }
+
+    #[test]
+    fn test_config_version() {
+        let toml = |version: &str| {
+            format!(
+                "version = \"{version}\"\n\
+                public_key = \"/pk\"\nsecret_key = \"/sk\"\nlisten = []\nfrom_the_future = 1\n\
+                [[peers]]\npublic_key = \"/p\"\nkeepalive = 25\n"
+            )
+        };
+
+        let ours = env!("CARGO_PKG_VERSION");
+        let config = Rosenpass::from_toml(&toml(ours)).unwrap();
+        assert_eq!(config.version.as_deref(), Some(ours));
+
+        let err = Rosenpass::from_toml(&toml("99.0.0"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("incompatible"), "{err}");
+        assert!(err.contains("from_the_future, peers[0].keepalive"), "{err}");
+
+        assert!(Rosenpass::from_toml(&toml("two")).is_err());
+
+        assert_eq!(parse_version("0.2.1"), Some((0, 2, 1)));
+        assert_eq!(parse_version("1.0.0-rc.1+abc"), Some((1, 0, 0)));
+        assert_eq!(parse_version("1.0"), None);
+        assert!(semver_compatible((1, 2, 0), (1, 5, 3)));
+        assert!(!semver_compatible((0, 2, 0), (0, 3, 0)));
+        assert!(!semver_compatible((1, 0, 0), (2, 0, 0)));
+    }
+
+    #[test]
+    fn test_cli_parse_source_address() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key source-address 192.0.2.7 \
+                peer public-key /p",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.source_address, Some("192.0.2.7".parse().unwrap()));
+
+        let args = split_str("public-key /a secret-key /b source-address 192.0.2.7:9999");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::InvalidIpAddr { addr, .. }) if addr == "192.0.2.7:9999"
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_log_file() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key log-file /var/log/rp.log \
+                peer public-key /p",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.log_file, Some(PathBuf::from("/var/log/rp.log")));
+
+        let args = split_str("public-key /a secret-key /b log-file /x log-file /y");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::AlreadySet("log-file"))
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_agent_socket() {
+        let args = split_str(
+            "public-key /my/public-key agent-socket /run/rp-agent.sock peer public-key /p",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(
+            config.agent_socket,
+            Some(PathBuf::from("/run/rp-agent.sock"))
+        );
+
+        let args = split_str("public-key /a agent-socket /x agent-socket /y");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::AlreadySet("agent-socket"))
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_max_peers() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key max-peers 1 \
+                peer public-key /p peer public-key /q",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.max_peers, Some(1));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("max-peers 1"), "{err}");
+
+        let args = split_str("public-key /a secret-key /b max-peers many");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::InvalidCount {
+                option: "max-peers",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_best_effort_listen() {
+        let args =
+            split_str("public-key /my/public-key secret-key /my/secret-key peer public-key /p");
+        assert!(!Rosenpass::parse_args(args).unwrap().best_effort_listen);
+
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key best-effort-listen \
+                peer public-key /p",
+        );
+        assert!(Rosenpass::parse_args(args).unwrap().best_effort_listen);
+    }
+
+    #[test]
+    fn test_cli_parse_bind_device() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key bind-device eth0 \
+                peer public-key /p",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.bind_device.as_deref(), Some("eth0"));
+
+        let args = split_str("public-key /a secret-key /b bind-device eth0 bind-device eth1");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::AlreadySet("bind-device"))
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_control_socket() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key control-socket /run/rp.sock \
+                peer public-key /p",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.control_socket, Some(PathBuf::from("/run/rp.sock")));
+
+        let args = split_str("public-key /a secret-key /b control-socket /c control-socket /d");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::AlreadySet("control-socket"))
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_socket_buffers() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key rcvbuf 1048576 sndbuf 65536 \
+                peer public-key /p",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.rcvbuf, Some(1048576));
+        assert_eq!(config.sndbuf, Some(65536));
+
+        let args = split_str("public-key /a secret-key /b rcvbuf 1M");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::InvalidSize {
+                option: "rcvbuf",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_peer_name() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key \
+                peer public-key /peer-a name alice peer public-key /peer-b",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.peers[0].name.as_deref(), Some("alice"));
+        assert_eq!(config.peers[1].name, None);
+
+        let args = split_str("public-key /a secret-key /b peer name alice name bob");
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::AlreadySet("peer name"))
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_wireguard_keepalive_only() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key \
+                peer public-key /peer-a wireguard wg0 pk persistent-keepalive 25 \
+                peer public-key /peer-b",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(
+            config.peers[0].wg,
+            Some(WireGuard {
+                device: "wg0".into(),
+                peer: "pk".into(),
+                extra_params: vec!["persistent-keepalive".into(), "25".into()],
+            })
+        );
+        assert_eq!(config.peers[1].wg, None);
+    }
+
+    #[test]
+    fn test_cli_parse_env_interpolation() {
+        std::env::set_var("RP_TEST_ENDPOINT", "peer.test:9999");
+        std::env::set_var("RP_TEST_EMPTY", "");
+        std::env::remove_var("RP_TEST_UNDEFINED");
+
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key \
+                peer public-key /peer endpoint ${RP_TEST_ENDPOINT} \
+                wireguard wg0 pk allowed-ips ${RP_TEST_UNDEFINED:-10.0.0.1/32},${RP_TEST_EMPTY:-fd00::1/128}",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.peers[0].endpoint.as_deref(), Some("peer.test:9999"));
+        assert_eq!(
+            config.peers[0].wg.as_ref().unwrap().extra_params,
+            vec!["allowed-ips", "10.0.0.1/32,fd00::1/128"]
+        );
+
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key \
+                peer public-key /peer endpoint ${RP_TEST_UNDEFINED}:9999",
+        );
+        assert!(matches!(
+            Rosenpass::parse_args(args),
+            Err(CliError::UndefinedVariable { name, .. }) if name == "RP_TEST_UNDEFINED"
+        ));
+
+        for endpoint in ["${RP_TEST_ENDPOINT", "${}:9999"] {
+            let args = split_str(&format!(
+                "public-key /my/public-key secret-key /my/secret-key \
+                    peer public-key /peer endpoint {endpoint}"
+            ));
+            assert!(matches!(
+                Rosenpass::parse_args(args),
+                Err(CliError::InvalidInterpolation(_))
+            ));
+        }
+
+        // `$` without a brace is not interpolated
+        assert_eq!(interpolate_env("a$b").unwrap(), "a$b");
+    }
+
+    #[test]
+    fn test_read_peers_jsonl() {
+        let mut config = Rosenpass::new("/my/public-key", "/my/secret-key");
+        let input = concat!(
+            r#"{"public_key": "/peer/0", "endpoint": "127.0.0.1:9999"}"#,
+            "\n\n",
+            r#"{"public_key": "/peer/1", "key_out": "/peer/1-out", "device": "wg0", "peer": "pk"}"#,
+            "\n",
+        );
+        config.read_peers_jsonl(input.as_bytes()).unwrap();
+
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers[0].endpoint.as_deref(), Some("127.0.0.1:9999"));
+        assert_eq!(config.peers[1].key_out, Some(PathBuf::from("/peer/1-out")));
+        assert_eq!(config.peers[1].wg.as_ref().unwrap().device, "wg0");
+
+        let input = "{\"public_key\": \"/peer/0\"}\n{\"endpoint\": 1}\n";
+        let err = config.read_peers_jsonl(input.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+    }
+
+    #[test]
+    fn test_cli_parse_psk_and_endpoint() {
+        // setting a psk must not count as setting the endpoint
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key \
+                peer public-key /peer/public-key preshared-key /peer/psk endpoint 127.0.0.1:9999",
+        );
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(config.peers[0].endpoint.as_deref(), Some("127.0.0.1:9999"));
+        assert_eq!(
+            config.peers[0].pre_shared_key,
+            Some(PathBuf::from("/peer/psk"))
+        );
+    }
+
     #[test]
     fn test_cli_parse_multiple_peers() {
         let args = split_str(
@@ -44,3 +44,5 @@ hash_domain!(_ckextract, res_enc, "responder handshake encryption");
 hash_domain_ns!(_ckextract, _user, "user");
 hash_domain_ns!(_user, _rp, "rosenpass.eu");
 hash_domain!(_rp, osk, "wireguard psk");
+hash_domain_ns!(_rp, seeded_keygen, "seeded key generation");
+hash_domain_ns!(_rp, log_redaction, "log redaction");
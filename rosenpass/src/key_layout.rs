@@ -0,0 +1,277 @@
+//! The two layouts a secret key directory can be stored in, and converting between them
+//!
+//! `rp genkey` writes a directory of three files: `pqsk` and `pqpk`, the rosenpass secret and
+//! public key, and `wgsk`, the base64 encoded WireGuard private key. The combined layout keeps
+//! the same three keys in a single file, which is easier to copy and back up. It is a transport
+//! and backup format only; keys are always loaded from the directory layout:
+//!
+//! ```text
+//! rosenpass-secret-keys 1
+//! pqsk <base64>
+//! pqpk <base64>
+//! wgsk <base64>
+//! ```
+//!
+//! [convert] reads and checks all of the source before it writes anything, so an incomplete
+//! source never leaves a half written destination behind.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context};
+use rosenpass_secret_memory::file::StoreSecret;
+use rosenpass_secret_memory::Secret;
+use rosenpass_util::b64::{b64_reader, b64_writer};
+use rosenpass_util::file::{fopen_r, LoadValue, ReadExactToEnd};
+use zeroize::Zeroizing;
+
+use crate::protocol::{SPk, SSk};
+
+/// First word of a file in the combined layout, followed by its version
+const COMBINED_HEADER: &str = "rosenpass-secret-keys";
+
+/// The version of the combined layout written by [SecretKeys::store_combined]
+const COMBINED_VERSION: u32 = 1;
+
+/// Length of a WireGuard private key in bytes
+pub const WGSK_LEN: usize = 32;
+
+/// The names of the keys, which are the file names in the directory layout
+const KEY_NAMES: [&str; 3] = ["pqsk", "pqpk", "wgsk"];
+
+/// The keys of a secret key directory, loaded from either layout
+pub struct SecretKeys {
+    pub pqsk: SSk,
+    pub pqpk: SPk,
+    pub wgsk: Secret<WGSK_LEN>,
+}
+
+/// Decode the base64 `value` into `buf`, which it has to fill exactly
+fn decode_b64(value: &[u8], buf: &mut [u8]) -> anyhow::Result<()> {
+    b64_reader(value).read_exact_to_end(buf)
+}
+
+/// Write `key` base64 encoded to `w`
+fn write_b64<W: Write>(w: &mut W, key: &[u8]) -> anyhow::Result<()> {
+    let mut writer = b64_writer(w);
+    writer.write_all(key)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Create the file `path` for secrets, only readable by its owner; with `force`, an existing file
+/// is overwritten
+fn create_secret_file(path: &Path, force: bool) -> anyhow::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .with_context(|| format!("could not create {path:?}"))
+}
+
+impl SecretKeys {
+    /// Load the keys from the three files of the directory `dir`
+    pub fn load_dir(dir: &Path) -> anyhow::Result<Self> {
+        let missing: Vec<_> = KEY_NAMES
+            .into_iter()
+            .filter(|name| !dir.join(name).is_file())
+            .collect();
+        ensure!(
+            missing.is_empty(),
+            "key directory {dir:?} is incomplete, it lacks {}",
+            missing.join(", ")
+        );
+
+        let pqsk = SSk::load(dir.join("pqsk"))
+            .with_context(|| format!("{:?} is no rosenpass secret key", dir.join("pqsk")))?;
+        let pqpk = SPk::load(dir.join("pqpk"))
+            .with_context(|| format!("{:?} is no rosenpass public key", dir.join("pqpk")))?;
+
+        // The base64 text is the secret itself; it is zeroized once dropped
+        let path = dir.join("wgsk");
+        let mut text = Zeroizing::new(Vec::new());
+        fopen_r(&path)?.read_to_end(&mut text)?;
+        let mut wgsk = Secret::zero();
+        decode_b64(text.trim_ascii(), wgsk.secret_mut())
+            .with_context(|| format!("{path:?} is no base64 WireGuard private key"))?;
+
+        Ok(Self { pqsk, pqpk, wgsk })
+    }
+
+    /// Load the keys from the file `path` in the combined layout
+    pub fn load_combined(path: &Path) -> anyhow::Result<Self> {
+        let mut text = Zeroizing::new(Vec::new());
+        fopen_r(path)
+            .with_context(|| format!("could not open {path:?}"))?
+            .read_to_end(&mut text)?;
+        Self::parse_combined(&text)
+            .with_context(|| format!("{path:?} is no combined secret key file"))
+    }
+
+    fn parse_combined(text: &[u8]) -> anyhow::Result<Self> {
+        let mut lines = text.split(|&b| b == b'\n').map(<[u8]>::trim_ascii);
+        let header = lines.next().unwrap_or_default();
+        let version = header
+            .strip_prefix(COMBINED_HEADER.as_bytes())
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .context("its first line is no rosenpass-secret-keys header")?
+            .trim();
+        ensure!(
+            version == COMBINED_VERSION.to_string(),
+            "it is of version {version:?}, this rosenpass reads version {COMBINED_VERSION}"
+        );
+
+        let (mut pqsk, mut pqpk, mut wgsk) = (SSk::zero(), SPk::zero(), Secret::zero());
+        let mut seen = [false; KEY_NAMES.len()];
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (name, value) = match line.iter().position(|&b| b == b' ') {
+                Some(at) => (&line[..at], line[at + 1..].trim_ascii()),
+                None => (line, &[][..]),
+            };
+            let name = String::from_utf8_lossy(name);
+            let Some(i) = KEY_NAMES.iter().position(|n| *n == name) else {
+                bail!("it holds the unknown key {name:?}");
+            };
+            ensure!(!seen[i], "it holds {name} twice");
+            seen[i] = true;
+            let buf = match i {
+                0 => pqsk.secret_mut().as_mut_slice(),
+                1 => pqpk.secret_mut().as_mut_slice(),
+                _ => wgsk.secret_mut().as_mut_slice(),
+            };
+            decode_b64(value, buf).with_context(|| format!("its {name} is invalid"))?;
+        }
+        let missing: Vec<_> = KEY_NAMES
+            .iter()
+            .zip(seen)
+            .filter(|(_, seen)| !seen)
+            .map(|(name, _)| *name)
+            .collect();
+        ensure!(missing.is_empty(), "it lacks {}", missing.join(", "));
+
+        Ok(Self { pqsk, pqpk, wgsk })
+    }
+
+    /// Store the keys as the three files of the directory `dir`, which is created only
+    /// accessible by its owner; with `force`, the files of an existing directory are replaced
+    pub fn store_dir(&self, dir: &Path, force: bool) -> anyhow::Result<()> {
+        ensure!(
+            force || !dir.exists(),
+            "{dir:?} already exists, refusing to overwrite it"
+        );
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(dir)
+            .with_context(|| format!("could not create {dir:?}"))?;
+
+        self.pqsk.store_secret(dir.join("pqsk"))?;
+        self.pqpk.store_secret(dir.join("pqpk"))?;
+        let mut file = create_secret_file(&dir.join("wgsk"), force)?;
+        write_b64(&mut file, self.wgsk.secret())?;
+        writeln!(file)?;
+        Ok(())
+    }
+
+    /// Store the keys as the file `path` in the combined layout, only readable by its owner;
+    /// with `force`, an existing file is overwritten
+    pub fn store_combined(&self, path: &Path, force: bool) -> anyhow::Result<()> {
+        ensure!(
+            force || !path.exists(),
+            "{path:?} already exists, refusing to overwrite it"
+        );
+        let mut file = create_secret_file(path, force)?;
+        writeln!(file, "{COMBINED_HEADER} {COMBINED_VERSION}")?;
+        let keys: [&[u8]; 3] = [self.pqsk.secret(), self.pqpk.secret(), self.wgsk.secret()];
+        for (name, key) in KEY_NAMES.iter().zip(keys) {
+            write!(file, "{name} ")?;
+            write_b64(&mut file, key)?;
+            writeln!(file)?;
+        }
+        Ok(())
+    }
+}
+
+/// Convert the keys at `from` to the other layout at `to`: a key directory into a combined
+/// file, a combined file into a key directory
+pub fn convert(from: &Path, to: &Path, force: bool) -> anyhow::Result<()> {
+    if from.is_dir() {
+        SecretKeys::load_dir(from)?.store_combined(to, force)
+    } else if from.is_file() {
+        SecretKeys::load_combined(from)?.store_dir(to, force)
+    } else {
+        bail!("{from:?} is neither a key directory nor a combined secret key file")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tmpdir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rosenpass-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_convert_round_trip() {
+        let dir = tmpdir("key-layout-round-trip");
+        let keys = SecretKeys {
+            pqsk: SSk::random(),
+            pqpk: SPk::random(),
+            wgsk: Secret::random(),
+        };
+        keys.store_dir(&dir.join("keys"), false).unwrap();
+
+        convert(&dir.join("keys"), &dir.join("combined"), false).unwrap();
+        convert(&dir.join("combined"), &dir.join("converted"), false).unwrap();
+        for name in KEY_NAMES {
+            assert_eq!(
+                std::fs::read(dir.join("keys").join(name)).unwrap(),
+                std::fs::read(dir.join("converted").join(name)).unwrap(),
+                "{name} differs after converting back and forth"
+            );
+        }
+
+        // the destination is not overwritten without force
+        assert!(convert(&dir.join("keys"), &dir.join("combined"), false).is_err());
+        convert(&dir.join("keys"), &dir.join("combined"), true).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_convert_incomplete_source() {
+        let dir = tmpdir("key-layout-incomplete");
+        let keys = dir.join("keys");
+        std::fs::create_dir_all(&keys).unwrap();
+        std::fs::write(
+            keys.join("wgsk"),
+            "yY3FIcdkBdaAmRTtuzfSbHsEpA6a6cBC5JYSLWAUEXk=\n",
+        )
+        .unwrap();
+
+        let err = convert(&keys, &dir.join("combined"), false).unwrap_err();
+        assert!(format!("{err:#}").contains("lacks pqsk, pqpk"), "{err:#}");
+        assert!(!dir.join("combined").exists());
+
+        let combined = dir.join("truncated");
+        std::fs::write(&combined, "rosenpass-secret-keys 1\nwgsk AAAA\n").unwrap();
+        assert!(convert(&combined, &dir.join("converted"), false).is_err());
+        assert!(!dir.join("converted").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+//! Where the secret key of a rosenpass instance comes from
+//!
+//! [FileKeySource] reads it from a file, as given with `secret-key`. [AgentKeySource] asks a
+//! key agent listening on a Unix domain socket instead, so the key need not be stored on the
+//! disk of this host, and further sources such as hardware tokens can implement [KeySource].
+//!
+//! The agent protocol is deliberately small: the client connects, writes the name of the key
+//! it wants followed by a newline and reads the reply until the agent closes the connection.
+//! `secret-key` is answered with the raw rosenpass secret key, `wgsk` with the base64 encoded
+//! WireGuard private key on a line of its own. A reply starting with `error` reports that the
+//! agent can not hand out the key.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use rosenpass_cipher_traits::Kem;
+use rosenpass_ciphers::kem::StaticKem;
+use rosenpass_util::file::LoadValue;
+use zeroize::Zeroizing;
+
+use crate::protocol::SSk;
+
+/// A source of the secret key of a rosenpass instance
+pub trait KeySource {
+    /// Produce the secret key
+    fn secret_key(&self) -> anyhow::Result<SSk>;
+}
+
+/// Reads the secret key from a file
+#[derive(Debug)]
+pub struct FileKeySource {
+    pub path: PathBuf,
+    /// Whether to log the path the key is loaded from
+    pub verbose: bool,
+}
+
+impl KeySource for FileKeySource {
+    fn secret_key(&self) -> anyhow::Result<SSk> {
+        crate::cli::load_key(
+            "secret key",
+            &self.path,
+            self.verbose,
+            Some(StaticKem::SK_LEN),
+            SSk::load,
+        )
+    }
+}
+
+/// How long a key agent may take to answer
+#[cfg(unix)]
+const AGENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Asks the key agent listening on `socket` for the keys, see the [module documentation](self)
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct AgentKeySource {
+    pub socket: PathBuf,
+}
+
+#[cfg(unix)]
+impl AgentKeySource {
+    /// Ask the agent for the key named `key`, returning the reply, which is zeroized once dropped
+    ///
+    /// Fails if the agent can not be reached or reports an error.
+    pub fn request(&self, key: &str) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let socket = &self.socket;
+        let mut stream = UnixStream::connect(socket)
+            .with_context(|| format!("could not connect to key agent {socket:?}"))?;
+        stream.set_read_timeout(Some(AGENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(AGENT_TIMEOUT))?;
+        writeln!(stream, "{key}")?;
+        let mut reply = Zeroizing::new(Vec::new());
+        stream
+            .read_to_end(&mut reply)
+            .with_context(|| format!("key agent {socket:?} did not answer the request {key:?}"))?;
+        if reply.starts_with(b"error") {
+            bail!(
+                "key agent {socket:?} refused the request {key:?}: {}",
+                String::from_utf8_lossy(&reply).trim()
+            );
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(unix)]
+impl KeySource for AgentKeySource {
+    fn secret_key(&self) -> anyhow::Result<SSk> {
+        let reply = self.request("secret-key")?;
+        if reply.len() != StaticKem::SK_LEN {
+            bail!(
+                "key agent {:?} sent {} bytes for the secret key, expected {}",
+                self.socket,
+                reply.len(),
+                StaticKem::SK_LEN
+            );
+        }
+        Ok(SSk::from_slice(&reply))
+    }
+}
@@ -2,8 +2,13 @@ pub mod app_server;
 pub mod cli;
 pub mod config;
 pub mod hash_domains;
+pub mod key_layout;
+pub mod key_source;
+pub mod log_file;
+pub mod log_redact;
 pub mod msgs;
 pub mod protocol;
+pub mod seeded_keygen;
 
 #[derive(thiserror::Error, Debug)]
 pub enum RosenpassError {
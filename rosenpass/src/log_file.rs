@@ -0,0 +1,90 @@
+//! Writing the log to a file instead of stderr
+//!
+//! The logger is set up before the configuration is read, so it always writes through
+//! [LogTarget]; [open] later redirects it into a file. The file is opened for appending and,
+//! on unix, opened again on `SIGHUP`, so a rotated log is continued in a fresh file.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Context;
+
+/// The log file and its path, if the log is redirected
+static LOG_FILE: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+/// Set by the `SIGHUP` handler; the next log message opens the file again
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Redirect the log to the file at `path`, appending to it
+pub fn open(path: &Path) -> anyhow::Result<()> {
+    let file =
+        open_append(path).with_context(|| format!("could not open log file {}", path.display()))?;
+    *LOG_FILE.lock().unwrap() = Some((path.to_owned(), file));
+    #[cfg(unix)]
+    install_reopen_signal()?;
+    Ok(())
+}
+
+/// Whether the log currently goes to a file rather than stderr
+pub fn is_open() -> bool {
+    LOG_FILE.lock().unwrap().is_some()
+}
+
+/// Open the log file again on `SIGHUP`
+#[cfg(unix)]
+fn install_reopen_signal() -> anyhow::Result<()> {
+    extern "C" fn on_sighup(_: libc::c_int) {
+        // only touches an atomic, which is async signal safe
+        REOPEN.store(true, Ordering::Relaxed);
+    }
+
+    // SAFETY: the handler only performs an atomic store
+    let previous = unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+    anyhow::ensure!(
+        previous != libc::SIG_ERR,
+        "could not install the SIGHUP handler: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(())
+}
+
+/// The writer the logger is built with, see [env_logger::Target::Pipe]
+///
+/// Writes go to the log file once one is [open], to stderr before that.
+pub struct LogTarget;
+
+impl Write for LogTarget {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut log_file = LOG_FILE.lock().unwrap();
+        let Some((path, file)) = log_file.as_mut() else {
+            return std::io::stderr().write(buf);
+        };
+        if REOPEN.swap(false, Ordering::Relaxed) {
+            // Keep writing to the old file if the new one can not be opened
+            match open_append(path) {
+                Ok(reopened) => *file = reopened,
+                Err(e) => eprintln!("could not reopen log file {}: {e}", path.display()),
+            }
+        }
+        file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some((_, file)) => file.flush(),
+            None => std::io::stderr().flush(),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Keeping the endpoints and identities of peers out of the log
+//!
+//! With `redact`, [enable] is called before the server starts; from then on [redacted]
+//! replaces each IP address, key or peer name it is given by a short hash. The same value
+//! always gets the same hash, so a peer can still be followed through the log. The hash is
+//! salted with a random value chosen at startup, as the few billion IPv4 addresses could
+//! otherwise simply be tried one by one; hashes from different runs can not be compared.
+
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+use rand::RngCore;
+use rosenpass_ciphers::KEY_LEN;
+
+use crate::hash_domains;
+
+/// The salt of the hashes, set once redaction is enabled
+static SALT: OnceLock<[u8; KEY_LEN]> = OnceLock::new();
+
+/// Number of bytes of the hash shown in place of a value
+const SHOWN_LEN: usize = 4;
+
+/// Replace the values passed to [redacted] by their hash from now on
+pub fn enable() {
+    SALT.get_or_init(|| {
+        let mut salt = [0u8; KEY_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        salt
+    });
+}
+
+/// Whether [enable] was called
+pub fn is_enabled() -> bool {
+    SALT.get().is_some()
+}
+
+/// `value` as it may appear in the log: unchanged, or its hash once redaction is enabled
+pub fn redacted<T: Display>(value: T) -> String {
+    let Some(salt) = SALT.get() else {
+        return value.to_string();
+    };
+    let hash = hash_domains::log_redaction()
+        .and_then(|h| h.mix(salt)?.mix(value.to_string().as_bytes()))
+        .map(|h| h.into_value());
+    match hash {
+        Ok(hash) => {
+            let hex: String = hash[..SHOWN_LEN]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            format!("redacted:{hex}")
+        }
+        Err(_) => "redacted".to_owned(),
+    }
+}
+
+/// Each of `values` [redacted], separated by commas
+pub fn redacted_list<T: Display>(values: &[T]) -> String {
+    values.iter().map(redacted).collect::<Vec<_>>().join(",")
+}
@@ -1,6 +1,7 @@
 use clap::Parser;
 use log::error;
 use rosenpass::cli::CliArgs;
+use rosenpass::log_file;
 use std::process::exit;
 
 /// Catches errors, prints them through the logger, then exits
@@ -15,6 +16,8 @@ pub fn main() {
             log::debug!("setting log level to {:?} (set via CLI parameter)", level);
             log_builder.filter_level(level); // set log level filter from CLI args if available
         }
+        // writes to stderr until a log file is opened
+        log_builder.target(env_logger::Target::Pipe(Box::new(log_file::LogTarget)));
         log_builder.init();
 
         // // check the effectiveness of the log level filter with the following lines:
@@ -26,10 +29,21 @@ pub fn main() {
         // error!("error dummy");
     }
 
+    if let Some(path) = args.log_file.as_deref() {
+        if let Err(e) = log_file::open(path) {
+            error!("{e:#}");
+            exit(1);
+        }
+    }
+
     match args.command.run() {
         Ok(_) => {}
         Err(e) => {
-            error!("{e}");
+            error!("{e:#}");
+            // whoever started us may not be watching the log file
+            if log_file::is_open() {
+                eprintln!("error: {e:#}");
+            }
             exit(1);
         }
     }
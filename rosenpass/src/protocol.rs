@@ -130,6 +130,15 @@ pub const RETRANSMIT_DELAY_JITTER: Timing = 0.5;
 
 pub const EVENT_GRACE: Timing = 0.0025;
 
+// Bounds for the operator-tunable timings in [ProtocolTiming]; the initiator
+// waits an extra ten seconds before rekeying (see [SessionPtr::retire_at]), and
+// both roles must rekey well before the session is rejected
+pub const REKEY_INITIATOR_DELAY: Timing = REKEY_AFTER_TIME_INITIATOR - REKEY_AFTER_TIME_RESPONDER;
+pub const REKEY_INTERVAL_MIN: Timing = 10.0;
+pub const REKEY_INTERVAL_MAX: Timing = REJECT_AFTER_TIME - 2.0 * REKEY_INITIATOR_DELAY;
+pub const HANDSHAKE_TIMEOUT_MIN: Timing = 1.0;
+pub const HANDSHAKE_TIMEOUT_MAX: Timing = REJECT_AFTER_TIME;
+
 // UTILITY FUNCTIONS /////////////////////////////
 
 // Event handling: For an event at T we sleep for T-now
@@ -161,6 +170,57 @@ pub type MsgBuf = Public<MAX_MESSAGE_LEN>;
 
 pub type PeerNo = usize;
 
+/// Timing parameters of the protocol which may be tuned, e.g. for high-latency links
+///
+/// The defaults are the values from the whitepaper; see [REKEY_AFTER_TIME_RESPONDER],
+/// [REKEY_AFTER_TIME_INITIATOR] and [REJECT_AFTER_TIME].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProtocolTiming {
+    /// Time after which the responder of a session starts a new handshake
+    pub rekey_after_time_responder: Timing,
+    /// Time after which the initiator of a session starts a new handshake
+    pub rekey_after_time_initiator: Timing,
+    /// Time after which an unfinished handshake is abandoned
+    pub handshake_timeout: Timing,
+}
+
+impl Default for ProtocolTiming {
+    fn default() -> Self {
+        Self {
+            rekey_after_time_responder: REKEY_AFTER_TIME_RESPONDER,
+            rekey_after_time_initiator: REKEY_AFTER_TIME_INITIATOR,
+            handshake_timeout: REJECT_AFTER_TIME,
+        }
+    }
+}
+
+impl ProtocolTiming {
+    /// Rekey every `interval` seconds, keeping the initiator's extra delay
+    pub fn with_rekey_interval(self, interval: Timing) -> Result<Self> {
+        ensure!(
+            (REKEY_INTERVAL_MIN..=REKEY_INTERVAL_MAX).contains(&interval),
+            "rekey interval must be between {REKEY_INTERVAL_MIN} and {REKEY_INTERVAL_MAX} seconds, got {interval}"
+        );
+        Ok(Self {
+            rekey_after_time_responder: interval,
+            rekey_after_time_initiator: interval + REKEY_INITIATOR_DELAY,
+            ..self
+        })
+    }
+
+    /// Abandon unfinished handshakes after `timeout` seconds
+    pub fn with_handshake_timeout(self, timeout: Timing) -> Result<Self> {
+        ensure!(
+            (HANDSHAKE_TIMEOUT_MIN..=HANDSHAKE_TIMEOUT_MAX).contains(&timeout),
+            "handshake timeout must be between {HANDSHAKE_TIMEOUT_MIN} and {HANDSHAKE_TIMEOUT_MAX} seconds, got {timeout}"
+        );
+        Ok(Self {
+            handshake_timeout: timeout,
+            ..self
+        })
+    }
+}
+
 /// Implementation of the cryptographic protocol
 ///
 /// The scope of this is:
@@ -175,6 +235,7 @@ pub type PeerNo = usize;
 #[derive(Debug)]
 pub struct CryptoServer {
     pub timebase: Timebase,
+    pub timing: ProtocolTiming,
 
     // Server Crypto
     pub sskm: SSk,
@@ -448,6 +509,7 @@ impl CryptoServer {
 
             // Defaults
             timebase: tb,
+            timing: ProtocolTiming::default(),
             biscuit_ctr: BiscuitId::new([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), // 1, LSB
             biscuit_keys: [BiscuitKey::new(), BiscuitKey::new()],
             peers: Vec::new(),
@@ -655,7 +717,8 @@ impl Mortal for IniHsPtr {
     }
 
     fn die_at(&self, srv: &CryptoServer) -> Option<Timing> {
-        self.created_at(srv).map(|t| t + REJECT_AFTER_TIME)
+        self.created_at(srv)
+            .map(|t| t + srv.timing.handshake_timeout)
     }
 }
 
@@ -674,8 +737,8 @@ impl Mortal for SessionPtr {
         use HandshakeRole::*;
         self.get(srv).as_ref().map(|p| {
             let wait = match p.handshake_role {
-                Initiator => REKEY_AFTER_TIME_INITIATOR,
-                Responder => REKEY_AFTER_TIME_RESPONDER,
+                Initiator => srv.timing.rekey_after_time_initiator,
+                Responder => srv.timing.rekey_after_time_responder,
             };
             p.created_at + wait
         })
@@ -0,0 +1,139 @@
+//! Deterministic key generation from a seed, for tests and disaster recovery only
+//!
+//! The same seed always yields the same keypair, so the keys are exactly as secret as the
+//! seed: anyone who learns or guesses it can recreate the secret key. Never use this for keys
+//! protecting real traffic unless the seed is kept like a secret key itself.
+//!
+//! liboqs only has a process wide random number generator; it is replaced once by one that
+//! reads the seeded stream of the current thread during [keypair_from_seed] and the system's
+//! random number generator everywhere else.
+
+use std::cell::RefCell;
+use std::sync::Once;
+
+use anyhow::{ensure, Context};
+use rand::RngCore;
+use rosenpass_cipher_traits::Kem;
+use rosenpass_ciphers::kem::{set_randombytes, StaticKem};
+use rosenpass_ciphers::KEY_LEN;
+use rosenpass_secret_memory::Secret;
+
+use crate::hash_domains;
+use crate::protocol::{SPk, SSk};
+
+/// Length of a seed in bytes
+pub const SEED_LEN: usize = KEY_LEN;
+
+/// A stream of pseudo random bytes derived from a seed, one hash per block
+struct SeededStream {
+    seed: Secret<SEED_LEN>,
+    block: Secret<KEY_LEN>,
+    used: usize,
+    counter: u64,
+}
+
+impl SeededStream {
+    fn new(seed: &[u8; SEED_LEN]) -> Self {
+        Self {
+            seed: Secret::from_slice(seed),
+            block: Secret::zero(),
+            used: KEY_LEN,
+            counter: 0,
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        for byte in buf {
+            if self.used == KEY_LEN {
+                let block = hash_domains::seeded_keygen()?
+                    .mix(self.seed.secret())?
+                    .mix(&self.counter.to_le_bytes())?
+                    .into_value();
+                self.block = Secret::from_slice(&block);
+                self.counter += 1;
+                self.used = 0;
+            }
+            *byte = self.block.secret()[self.used];
+            self.used += 1;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// The stream liboqs draws from on this thread, while [keypair_from_seed] runs
+    static STREAM: RefCell<Option<SeededStream>> = const { RefCell::new(None) };
+}
+
+unsafe extern "C" fn randombytes(buf: *mut u8, len: usize) {
+    // SAFETY: liboqs passes a buffer of `len` writable bytes
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+    STREAM.with_borrow_mut(|stream| match stream {
+        // An error can not be passed back through liboqs
+        Some(stream) => stream
+            .fill(buf)
+            .expect("could not derive random bytes from the seed"),
+        None => rand::rngs::OsRng.fill_bytes(buf),
+    })
+}
+
+/// Parse a seed given as hexadecimal digits
+pub fn parse_seed(hex: &str) -> anyhow::Result<[u8; SEED_LEN]> {
+    ensure!(
+        hex.len() == 2 * SEED_LEN,
+        "a seed is {} hexadecimal digits, got {}",
+        2 * SEED_LEN,
+        hex.len()
+    );
+    let mut seed = [0u8; SEED_LEN];
+    for (byte, digits) in seed.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).ok();
+        *byte = digits
+            .and_then(|d| u8::from_str_radix(d, 16).ok())
+            .with_context(|| format!("the seed {hex:?} is not hexadecimal"))?;
+    }
+    Ok(seed)
+}
+
+/// Generate the keypair belonging to `seed`
+///
+/// See the [module documentation](self) for why this is insecure for production.
+pub fn keypair_from_seed(seed: &[u8; SEED_LEN]) -> anyhow::Result<(SSk, SPk)> {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| set_randombytes(randombytes));
+
+    let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
+    STREAM.set(Some(SeededStream::new(seed)));
+    let res = StaticKem::keygen(sk.secret_mut(), pk.secret_mut());
+    STREAM.set(None);
+    res?;
+    Ok((sk, pk))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keypair_from_seed_is_deterministic() {
+        let seed = parse_seed(&"5e".repeat(SEED_LEN)).unwrap();
+        let (sk1, pk1) = keypair_from_seed(&seed).unwrap();
+        let (sk2, pk2) = keypair_from_seed(&seed).unwrap();
+        assert_eq!(sk1.secret(), sk2.secret());
+        assert_eq!(pk1.secret(), pk2.secret());
+
+        let other = parse_seed(&"5f".repeat(SEED_LEN)).unwrap();
+        let (sk3, _) = keypair_from_seed(&other).unwrap();
+        assert_ne!(sk1.secret(), sk3.secret());
+    }
+
+    #[test]
+    fn test_parse_seed() {
+        assert!(parse_seed("00").is_err());
+        assert!(parse_seed(&"zz".repeat(SEED_LEN)).is_err());
+        assert_eq!(
+            parse_seed(&"ff".repeat(SEED_LEN)).unwrap(),
+            [0xff; SEED_LEN]
+        );
+    }
+}
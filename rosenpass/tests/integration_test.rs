@@ -1,4 +1,10 @@
-use std::{fs, net::UdpSocket, path::PathBuf, process::Stdio, time::Duration};
+use std::{
+    fs,
+    net::UdpSocket,
+    path::{Path, PathBuf},
+    process::{Output, Stdio},
+    time::Duration,
+};
 
 const BIN: &str = "rosenpass";
 
@@ -28,6 +34,132 @@ fn generate_keys() {
     fs::remove_dir_all(&tmpdir).unwrap();
 }
 
+// check that the self-test passes
+#[test]
+fn self_test() {
+    let output = test_bin::get_test_bin(BIN)
+        .arg("self-test")
+        .output()
+        .expect("Failed to start {BIN}");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "self-test passed\n"
+    );
+}
+
+// check that the benchmark runs and reports its results as JSON
+#[test]
+fn bench_json() {
+    let output = test_bin::get_test_bin(BIN)
+        .args(["bench", "--iterations", "2", "--json"])
+        .output()
+        .expect("Failed to start {BIN}");
+
+    assert!(output.status.success());
+    let res: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(res["handshakes"], 2);
+    assert!(res["handshakes_per_second"].as_f64().unwrap() > 0.0);
+    assert!(res["latency_ms"]["avg"].as_f64().unwrap() > 0.0);
+}
+
+// check that a truncated key file is reported with its size
+#[test]
+fn truncated_key_file() {
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("truncated-key");
+    fs::create_dir_all(&tmpdir).unwrap();
+
+    let secret_key_path = tmpdir.join("secret-key");
+    let public_key_path = tmpdir.join("public-key");
+    let output = test_bin::get_test_bin(BIN)
+        .args(["gen-keys", "--force", "--secret-key"])
+        .arg(&secret_key_path)
+        .arg("--public-key")
+        .arg(&public_key_path)
+        .output()
+        .expect("Failed to start {BIN}");
+    assert!(output.status.success());
+
+    let truncated_path = tmpdir.join("truncated-public-key");
+    fs::write(
+        &truncated_path,
+        &fs::read(&public_key_path).unwrap()[..1000],
+    )
+    .unwrap();
+
+    let output = test_bin::get_test_bin(BIN)
+        .args(["exchange", "secret-key"])
+        .arg(&secret_key_path)
+        .arg("public-key")
+        .arg(&public_key_path)
+        .args(["peer", "public-key"])
+        .arg(&truncated_path)
+        .output()
+        .expect("Failed to start {BIN}");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is 1000 bytes, expected"));
+
+    // cleanup
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that convert turns a key directory into a combined file and back without changing a key
+#[test]
+fn convert_key_layout() {
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("convert-key-layout");
+    let _ = fs::remove_dir_all(&tmpdir);
+    let keys = tmpdir.join("keys");
+    fs::create_dir_all(&keys).unwrap();
+
+    let output = test_bin::get_test_bin(BIN)
+        .args(["gen-keys", "--secret-key"])
+        .arg(keys.join("pqsk"))
+        .arg("--public-key")
+        .arg(keys.join("pqpk"))
+        .output()
+        .expect("Failed to start {BIN}");
+    assert!(output.status.success());
+    fs::write(
+        keys.join("wgsk"),
+        "yY3FIcdkBdaAmRTtuzfSbHsEpA6a6cBC5JYSLWAUEXk=\n",
+    )
+    .unwrap();
+
+    let convert = |from: &PathBuf, to: &PathBuf| {
+        test_bin::get_test_bin(BIN)
+            .arg("convert")
+            .arg(from)
+            .arg(to)
+            .output()
+            .expect("Failed to start {BIN}")
+    };
+    let combined = tmpdir.join("combined");
+    let converted = tmpdir.join("converted");
+    assert!(convert(&keys, &combined).status.success());
+    assert!(fs::read_to_string(&combined)
+        .unwrap()
+        .starts_with("rosenpass-secret-keys 1\n"));
+    assert!(convert(&combined, &converted).status.success());
+    for file in ["pqsk", "pqpk", "wgsk"] {
+        assert_eq!(
+            fs::read(keys.join(file)).unwrap(),
+            fs::read(converted.join(file)).unwrap(),
+            "{file} differs after converting back and forth"
+        );
+    }
+
+    // an incomplete directory is refused before anything is written
+    fs::remove_file(keys.join("wgsk")).unwrap();
+    let incomplete = tmpdir.join("incomplete");
+    let output = convert(&keys, &incomplete);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("lacks wgsk"));
+    assert!(!incomplete.exists());
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
 fn find_udp_socket() -> u16 {
     for port in 1025..=u16::MAX {
         if UdpSocket::bind(("127.0.0.1", port)).is_ok() {
@@ -117,3 +249,1104 @@ fn check_exchange() {
     // cleanup
     fs::remove_dir_all(&tmpdir).unwrap();
 }
+
+// check that with redact, the log names no IP address or base64 key of a peer
+#[test]
+fn check_exchange_redact() {
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("exchange-redact");
+    let _ = fs::remove_dir_all(&tmpdir);
+    fs::create_dir_all(&tmpdir).unwrap();
+
+    let secret_key_paths = [tmpdir.join("secret-key-0"), tmpdir.join("secret-key-1")];
+    let public_key_paths = [tmpdir.join("public-key-0"), tmpdir.join("public-key-1")];
+    let shared_key_paths = [tmpdir.join("shared-key-0"), tmpdir.join("shared-key-1")];
+    for (secret_key_path, pub_key_path) in secret_key_paths.iter().zip(public_key_paths.iter()) {
+        let status = test_bin::get_test_bin(BIN)
+            .args(["gen-keys", "--secret-key"])
+            .arg(secret_key_path)
+            .arg("--public-key")
+            .arg(pub_key_path)
+            .status()
+            .expect("Failed to start {BIN}");
+        assert!(status.success());
+    }
+
+    let port = find_udp_socket();
+    let listen_addr = format!("127.0.0.1:{port}");
+    let exchange = |own: usize, extra: &[&str]| {
+        test_bin::get_test_bin(BIN)
+            .args(["--verbose", "exchange", "secret-key"])
+            .arg(&secret_key_paths[own])
+            .arg("public-key")
+            .arg(&public_key_paths[own])
+            .args(extra)
+            .args(["verbose", "redact", "peer", "public-key"])
+            .arg(&public_key_paths[1 - own])
+            .args(["name", "alice"])
+            .args(if own == 1 {
+                vec!["endpoint", &listen_addr]
+            } else {
+                vec![]
+            })
+            .arg("outfile")
+            .arg(&shared_key_paths[own])
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to start {BIN}")
+    };
+    let server = exchange(0, &["listen", &listen_addr]);
+    std::thread::sleep(Duration::from_millis(500));
+    let client = exchange(1, &[]);
+    std::thread::sleep(Duration::from_secs(2));
+
+    let mut logs = String::new();
+    for mut child in [server, client] {
+        child.kill().unwrap();
+        logs += &String::from_utf8_lossy(&child.wait_with_output().unwrap().stderr);
+    }
+
+    let shared_keys: Vec<_> = shared_key_paths
+        .iter()
+        .map(|p| fs::read_to_string(p).unwrap())
+        .collect();
+    assert_eq!(shared_keys[0], shared_keys[1]);
+
+    assert!(logs.contains("redacted:"), "{logs}");
+    assert!(!logs.contains("127.0.0.1"), "{logs}");
+    assert!(!logs.contains("alice"), "{logs}");
+    let is_b64_key = |word: &str| {
+        word.len() == 44
+            && word.ends_with('=')
+            && word[..43]
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+    };
+    assert!(
+        !logs
+            .split(|c: char| c.is_whitespace() || c == ',' || c == ':')
+            .any(is_b64_key),
+        "{logs}"
+    );
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that two tunnels in one process exchange keys with each other
+#[test]
+fn check_exchange_config_tunnels() {
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("exchange-config-tunnels");
+    fs::create_dir_all(&tmpdir).unwrap();
+
+    let ports = [find_udp_socket(), find_udp_socket()];
+    let ports = if ports[0] == ports[1] {
+        [ports[0], ports[0] + 1]
+    } else {
+        ports
+    };
+
+    let mut config_paths = Vec::new();
+    for i in 0..2 {
+        let output = test_bin::get_test_bin(BIN)
+            .args(["gen-keys", "--secret-key"])
+            .arg(tmpdir.join(format!("secret-key-{i}")))
+            .arg("--public-key")
+            .arg(tmpdir.join(format!("public-key-{i}")))
+            .output()
+            .expect("Failed to start {BIN}");
+        assert!(output.status.success());
+
+        // each tunnel has the other one as its peer, the second one initiates
+        let other = 1 - i;
+        let endpoint = match i {
+            1 => format!("endpoint = \"127.0.0.1:{}\"\n", ports[other]),
+            _ => String::new(),
+        };
+        let config = format!(
+            "public_key = {:?}\n\
+             secret_key = {:?}\n\
+             listen = [\"127.0.0.1:{}\"]\n\
+             \n\
+             [[peers]]\n\
+             public_key = {:?}\n\
+             {endpoint}\
+             key_out = {:?}\n",
+            tmpdir.join(format!("public-key-{i}")),
+            tmpdir.join(format!("secret-key-{i}")),
+            ports[i],
+            tmpdir.join(format!("public-key-{other}")),
+            tmpdir.join(format!("shared-key-{i}")),
+        );
+        let config_path = tmpdir.join(format!("config-{i}.toml"));
+        fs::write(&config_path, config).unwrap();
+        config_paths.push(config_path);
+    }
+
+    let mut tunnels = test_bin::get_test_bin(BIN)
+        .arg("exchange-config")
+        .args(&config_paths)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start {BIN}");
+
+    // give them some time to do the key exchange
+    std::thread::sleep(Duration::from_secs(2));
+    tunnels.kill().unwrap();
+    tunnels.wait().unwrap();
+
+    let shared_keys: Vec<_> = (0..2)
+        .map(|i| fs::read_to_string(tmpdir.join(format!("shared-key-{i}"))).unwrap())
+        .collect();
+    assert_eq!(shared_keys[0], shared_keys[1]);
+
+    // a missing config file fails all tunnels before any of them starts
+    let output = test_bin::get_test_bin(BIN)
+        .arg("exchange-config")
+        .arg(&config_paths[0])
+        .arg(tmpdir.join("missing.toml"))
+        .output()
+        .expect("Failed to start {BIN}");
+    assert!(!output.status.success());
+
+    // cleanup
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+/// find a port `p` for which both `p` and `p + 1` are free, as `rp` puts
+/// WireGuard on the port after the one used by rosenpass
+#[cfg(target_os = "linux")]
+fn find_udp_port_pair(start: u16) -> u16 {
+    for port in start..u16::MAX {
+        if UdpSocket::bind(("127.0.0.1", port)).is_ok()
+            && UdpSocket::bind(("127.0.0.1", port + 1)).is_ok()
+        {
+            return port;
+        }
+    }
+    panic!("no free pair of UDP ports found");
+}
+
+/// run the `rp` script from the repository root with our rosenpass binary in the PATH
+#[cfg(target_os = "linux")]
+fn rp() -> std::process::Command {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let bin_dir = PathBuf::from(env!("CARGO_BIN_EXE_rosenpass"))
+        .parent()
+        .unwrap()
+        .to_owned();
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let path =
+        std::env::join_paths(std::iter::once(bin_dir).chain(std::env::split_paths(&path))).unwrap();
+
+    let mut cmd = std::process::Command::new(manifest_dir.join("../rp"));
+    cmd.env("PATH", path);
+    cmd
+}
+
+/// run the `rp` script like [rp], but finding the commands in `bin_dir` before all others
+#[cfg(target_os = "linux")]
+fn rp_with_tools(bin_dir: &Path) -> std::process::Command {
+    let mut cmd = rp();
+    let path = cmd
+        .get_envs()
+        .find(|(k, _)| *k == "PATH")
+        .and_then(|(_, v)| v)
+        .map(|v| v.to_owned())
+        .unwrap_or_default();
+    let path = std::env::join_paths(
+        std::iter::once(bin_dir.to_owned()).chain(std::env::split_paths(&path)),
+    )
+    .unwrap();
+    cmd.env("PATH", path);
+    cmd
+}
+
+/// WireGuard public key of the peers in rp tests that never bring up a tunnel
+#[cfg(target_os = "linux")]
+const TEST_WGPK: &str = "yY3FIcdkBdaAmRTtuzfSbHsEpA6a6cBC5JYSLWAUEXk=";
+
+/// create the temporary directory `name` for an rp test, removing what an earlier run left
+#[cfg(target_os = "linux")]
+fn rp_tmpdir(name: &str) -> PathBuf {
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&tmpdir);
+    fs::create_dir_all(&tmpdir).unwrap();
+    tmpdir
+}
+
+/// create the keys directories `secret` and `public` in `tmpdir`, for rp tests that only
+/// parse them: the secret keys are empty, the peer's WireGuard public key is [TEST_WGPK]
+#[cfg(target_os = "linux")]
+fn rp_key_dirs(tmpdir: &Path) -> (PathBuf, PathBuf) {
+    let skdir = tmpdir.join("secret");
+    let pkdir = tmpdir.join("public");
+    fs::create_dir_all(&skdir).unwrap();
+    fs::create_dir_all(&pkdir).unwrap();
+    for file in ["pqsk", "pqpk", "wgsk"] {
+        fs::write(skdir.join(file), "").unwrap();
+    }
+    fs::write(pkdir.join("wgpk"), format!("{TEST_WGPK}\n")).unwrap();
+    fs::write(pkdir.join("pqpk"), "").unwrap();
+    (skdir, pkdir)
+}
+
+/// run `rp show-config SKDIR OWN_OPTIONS...`, followed by `peer PKDIR OPTIONS...` for each of
+/// `peers`
+#[cfg(target_os = "linux")]
+fn rp_show_config(skdir: &Path, own_options: &[&str], peers: &[(&Path, &[&str])]) -> Output {
+    let mut cmd = rp();
+    cmd.arg("show-config").arg(skdir).args(own_options);
+    for (pkdir, options) in peers {
+        cmd.arg("peer").arg(pkdir).args(*options);
+    }
+    cmd.output().unwrap()
+}
+
+/// everything the successful rp invocation `output` printed, standard output first
+#[cfg(target_os = "linux")]
+fn rp_shown(output: &Output) -> String {
+    let shown = String::from_utf8_lossy(&output.stdout).into_owned()
+        + &String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{shown}");
+    shown
+}
+
+/// the error output of the failed rp invocation `output`
+#[cfg(target_os = "linux")]
+fn rp_failed(output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(!output.status.success(), "{stderr}");
+    stderr
+}
+
+/// the value of the setting shown on the line starting with `prefix` in `shown`
+#[cfg(target_os = "linux")]
+fn rp_shown_setting(shown: &str, prefix: &str) -> String {
+    shown
+        .lines()
+        .find_map(|l| l.strip_prefix(prefix))
+        .unwrap_or_else(|| panic!("no {prefix:?} in {shown}"))
+        .to_owned()
+}
+
+// check that two rp exchange instances on loopback bring up a WireGuard session
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_exchange() {
+    use std::os::unix::process::CommandExt;
+    use std::time::Instant;
+
+    // creating WireGuard interfaces requires root and the wg tool
+    let is_root = std::process::Command::new("id")
+        .arg("-u")
+        .output()
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim() == "0")
+        .unwrap_or(false);
+    let has_wg = std::process::Command::new("wg")
+        .arg("--version")
+        .output()
+        .is_ok();
+    if !is_root || !has_wg {
+        eprintln!("skipping check_rp_exchange: needs root and wireguard-tools");
+        return;
+    }
+
+    let tmpdir = rp_tmpdir("rp-exchange");
+
+    let secret_key_dirs = [tmpdir.join("sk-0"), tmpdir.join("sk-1")];
+    let public_key_dirs = [tmpdir.join("pk-0"), tmpdir.join("pk-1")];
+    let devs = ["rptest0", "rptest1"];
+
+    // generate key pairs
+    for (sk, pk) in secret_key_dirs.iter().zip(public_key_dirs.iter()) {
+        let status = rp().arg("genkey").arg(sk).status().unwrap();
+        assert!(status.success());
+        let status = rp().arg("pubkey").arg(sk).arg(pk).status().unwrap();
+        assert!(status.success());
+    }
+
+    // every instance listens on its own port and has the other one as its peer
+    let ports = {
+        let first = find_udp_port_pair(1025);
+        [first, find_udp_port_pair(first + 2)]
+    };
+    let mut instances: Vec<_> = (0..2)
+        .map(|i| {
+            let other = 1 - i;
+            rp().arg("exchange")
+                .arg(&secret_key_dirs[i])
+                .args(["dev", devs[i]])
+                .args(["listen", &format!("127.0.0.1:{}", ports[i])])
+                .arg("peer")
+                .arg(&public_key_dirs[other])
+                .args(["endpoint", &format!("127.0.0.1:{}", ports[other])])
+                .args(["persistent-keepalive", "1"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                // own process group, so rosenpass can be stopped together with rp
+                .process_group(0)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    // wait for a WireGuard handshake on both interfaces
+    let handshake_done = |dev: &str| {
+        std::process::Command::new("wg")
+            .args(["show", dev, "latest-handshakes"])
+            .output()
+            .map(|o| {
+                String::from_utf8_lossy(&o.stdout)
+                    .split_whitespace()
+                    .nth(1)
+                    .is_some_and(|ts| ts != "0")
+            })
+            .unwrap_or(false)
+    };
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let mut success = false;
+    while Instant::now() < deadline {
+        if devs.iter().all(|dev| handshake_done(dev)) {
+            success = true;
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+
+    // stop both instances and remove leftover interfaces before asserting
+    for instance in instances.iter_mut() {
+        let _ = std::process::Command::new("kill")
+            .args(["-TERM", "--", &format!("-{}", instance.id())])
+            .status();
+        let _ = instance.wait();
+    }
+    for dev in devs {
+        let _ = rp().args(["down", "dev", dev]).status();
+    }
+    fs::remove_dir_all(&tmpdir).unwrap();
+
+    assert!(success, "no WireGuard handshake within 20 seconds");
+}
+
+// check that rp export and import reproduce the public keys byte for byte
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_export_import() {
+    let tmpdir = rp_tmpdir("rp-export-import");
+    let (_, pkdir) = rp_key_dirs(&tmpdir);
+
+    let pqpk: Vec<u8> = (0..524160u32).map(|i| (i * 7 + i / 256) as u8).collect();
+    fs::write(pkdir.join("pqpk"), &pqpk).unwrap();
+    fs::write(pkdir.join("psk"), [0x5au8; 32]).unwrap();
+
+    let bundle = tmpdir.join("bundle");
+    let status = rp()
+        .arg("export")
+        .arg(&pkdir)
+        .arg(&bundle)
+        .arg("with-psk")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let imported = tmpdir.join("imported");
+    let status = rp()
+        .arg("import")
+        .arg(&bundle)
+        .arg(&imported)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    for file in ["wgpk", "pqpk", "psk"] {
+        assert_eq!(
+            fs::read(pkdir.join(file)).unwrap(),
+            fs::read(imported.join(file)).unwrap(),
+            "{file} differs after export and import"
+        );
+    }
+
+    // a bundle of an unknown version is rejected
+    let bundle_v2 = tmpdir.join("bundle-v2");
+    let contents = fs::read_to_string(&bundle).unwrap();
+    fs::write(
+        &bundle_v2,
+        contents.replacen("rosenpass-public-keys 1", "rosenpass-public-keys 2", 1),
+    )
+    .unwrap();
+    let output = rp()
+        .arg("import")
+        .arg(&bundle_v2)
+        .arg(tmpdir.join("rejected"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(!tmpdir.join("rejected").exists());
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that confirm applies nothing without a terminal to confirm on, unless yes is given
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_confirm() {
+    let tmpdir = rp_tmpdir("rp-confirm");
+    let (_, pkdir) = rp_key_dirs(&tmpdir);
+
+    let bundle = tmpdir.join("bundle");
+    let output = rp()
+        .arg("confirm")
+        .arg("export")
+        .arg(&pkdir)
+        .arg(&bundle)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Planned actions"), "{stderr}");
+    assert!(!bundle.exists());
+
+    let status = rp()
+        .args(["confirm", "yes", "export"])
+        .arg(&pkdir)
+        .arg(&bundle)
+        .stdin(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(bundle.exists());
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that rp exchange takes peers from a CSV file and names the line of a bad row
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_peers_csv() {
+    let tmpdir = rp_tmpdir("rp-peers-csv");
+    let (skdir, _) = rp_key_dirs(&tmpdir);
+    let peers = [
+        ("alice", TEST_WGPK),
+        ("bob", "hN0x4mWm9Jv0yH0QmKhCk7nSfGJ2sC6oX0G8z8mW0Ws="),
+    ];
+    for (name, wgpk) in peers {
+        let pkdir = tmpdir.join(name);
+        fs::create_dir_all(&pkdir).unwrap();
+        fs::write(pkdir.join("wgpk"), format!("{wgpk}\n")).unwrap();
+        fs::write(pkdir.join("pqpk"), name).unwrap();
+    }
+
+    let csv = tmpdir.join("peers.csv");
+    let peers_csv = ["peers-csv", csv.to_str().unwrap()];
+    fs::write(
+        &csv,
+        "name,public_keys_dir,endpoint,keepalive,allowed_ips\n\
+         alice,alice,192.0.2.1:9999,25,\"10.0.0.1/32,10.0.1.0/24\"\n\
+         \n\
+         bob,bob,,,\n",
+    )
+    .unwrap();
+    let shown = rp_shown(&rp_show_config(&skdir, &peers_csv, &[]));
+    assert!(
+        shown.contains(&format!("peer {}", tmpdir.join("alice").display())),
+        "{shown}"
+    );
+    assert!(shown.contains("  name bob"), "{shown}");
+    assert!(shown.contains("  endpoint 192.0.2.1:9999"), "{shown}");
+    assert!(shown.contains("  persistent-keepalive 25"), "{shown}");
+    assert!(
+        shown.contains("  allowed-ips 10.0.0.1/32,10.0.1.0/24"),
+        "{shown}"
+    );
+
+    fs::write(
+        &csv,
+        "name,public_keys_dir,keepalive\nalice,alice,25\nbob,bob,forever\n",
+    )
+    .unwrap();
+    let stderr = rp_failed(&rp_show_config(&skdir, &peers_csv, &[]));
+    assert!(stderr.contains("line 3: keepalive"), "{stderr}");
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that persistent-keepalive passes 0 on to turn keepalive off and rejects intervals
+// WireGuard can not store
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_persistent_keepalive() {
+    let tmpdir = rp_tmpdir("rp-persistent-keepalive");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+    let show = |interval: &str| {
+        rp_show_config(
+            &skdir,
+            &[],
+            &[(&pkdir, &["persistent-keepalive", interval])],
+        )
+    };
+
+    let shown = rp_shown(&show("0"));
+    assert_eq!(rp_shown_setting(&shown, "  persistent-keepalive "), "0");
+
+    let shown = rp_shown(&show("25"));
+    assert_eq!(rp_shown_setting(&shown, "  persistent-keepalive "), "25");
+
+    let stderr = rp_failed(&show("65536"));
+    assert!(
+        stderr.contains("persistent-keepalive must be between 0 and 65535 seconds, got \"65536\""),
+        "{stderr}"
+    );
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that repeated allowed-ips options are merged with comma separated lists, each range
+// listed once
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_repeated_allowed_ips() {
+    let tmpdir = rp_tmpdir("rp-repeated-allowed-ips");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+
+    let shown = rp_shown(&rp_show_config(
+        &skdir,
+        &["print-wg-conf"],
+        &[(
+            &pkdir,
+            &[
+                "allowed-ips",
+                "10.0.0.1/32,10.0.1.0/24",
+                "allowed-ips",
+                "10.0.0.1/32",
+                "allowed-ips",
+                "fd00::/8,10.0.1.0/24",
+            ],
+        )],
+    ));
+    assert_eq!(
+        rp_shown_setting(&shown, "  allowed-ips "),
+        "10.0.0.1/32,10.0.1.0/24,fd00::/8"
+    );
+    assert_eq!(
+        rp_shown_setting(&shown, "AllowedIPs = "),
+        "10.0.0.1/32, 10.0.1.0/24, fd00::/8"
+    );
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that the interface zone of a link-local endpoint is kept for WireGuard's port above it
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_scoped_endpoint() {
+    let tmpdir = rp_tmpdir("rp-scoped-endpoint");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+
+    let shown = rp_shown(&rp_show_config(
+        &skdir,
+        &["print-wg-conf"],
+        &[(&pkdir, &["endpoint", "[fe80::1%lo]:9999"])],
+    ));
+    assert_eq!(
+        rp_shown_setting(&shown, "  wireguard-endpoint "),
+        "[fe80::1%lo]:10000"
+    );
+    assert_eq!(
+        rp_shown_setting(&shown, "Endpoint = "),
+        "[fe80::1%lo]:10000"
+    );
+
+    let stderr = rp_failed(&rp_show_config(
+        &skdir,
+        &[],
+        &[(&pkdir, &["endpoint", "[fe80::1%no-such-if0]:9999"])],
+    ));
+    assert!(stderr.contains("which does not exist"), "{stderr}");
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that auto-allowed-ips derives a host prefix from the peer's address
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_auto_allowed_ips() {
+    let tmpdir = rp_tmpdir("rp-auto-allowed-ips");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+    let show = |options: &[&str]| rp_shown(&rp_show_config(&skdir, &[], &[(&pkdir, options)]));
+
+    let v4 = show(&["address", "10.0.0.2/24", "auto-allowed-ips"]);
+    assert!(v4.contains("allowed-ips 10.0.0.2/32"), "{v4}");
+
+    let v6 = show(&["address", "fd00::2", "auto-allowed-ips"]);
+    assert!(v6.contains("allowed-ips fd00::2/128"), "{v6}");
+
+    // explicit allowed-ips win over the derived prefix
+    let explicit = show(&[
+        "address",
+        "10.0.0.2",
+        "auto-allowed-ips",
+        "allowed-ips",
+        "10.0.1.0/24",
+    ]);
+    assert!(explicit.contains("allowed-ips 10.0.1.0/24"), "{explicit}");
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that rp exchange expands allowed-ips exclusions into the remaining prefixes
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_allowed_ips_exclusion() {
+    let tmpdir = rp_tmpdir("rp-allowed-ips-exclusion");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+    let allowed_ips = |ips: &str| {
+        let shown = rp_shown(&rp_show_config(
+            &skdir,
+            &[],
+            &[(&pkdir, &["allowed-ips", ips])],
+        ));
+        rp_shown_setting(&shown, "  allowed-ips ")
+    };
+
+    assert_eq!(allowed_ips("10.0.0.0/16,!10.0.128.0/17"), "10.0.0.0/17");
+    assert_eq!(
+        allowed_ips("10.0.0.0/24,!10.0.0.0/26"),
+        "10.0.0.128/25,10.0.0.64/26"
+    );
+    // an exclusion only splits the prefixes of its own family
+    assert_eq!(
+        allowed_ips("192.168.0.0/23,fd00::/8,!192.168.1.0/24"),
+        "192.168.0.0/24,fd00::/8"
+    );
+    assert_eq!(
+        allowed_ips("fd00::/16,!fd00::/18"),
+        "fd00:8000::/17,fd00:4000::/18"
+    );
+    assert_eq!(
+        allowed_ips("2001:db8::/126,!2001:db8::1/128"),
+        "2001:db8::2/127,2001:db8::/128"
+    );
+    assert_eq!(allowed_ips("10.0.0.0/8,!0.0.0.0/0"), "(none)");
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that rp exchange says so when the private and public keys directories are swapped
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_swapped_key_dirs() {
+    let tmpdir = rp_tmpdir("rp-swapped-key-dirs");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+
+    let stderr = rp_failed(&rp_show_config(&pkdir, &[], &[(&pkdir, &[])]));
+    assert!(
+        stderr.contains("this looks like a public keys directory"),
+        "{stderr}"
+    );
+
+    let stderr = rp_failed(&rp_show_config(&skdir, &[], &[(&skdir, &[])]));
+    assert!(
+        stderr.contains("this looks like a private keys directory"),
+        "{stderr}"
+    );
+
+    rp_shown(&rp_show_config(&skdir, &[], &[(&pkdir, &[])]));
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that whitespace around the WireGuard private key, e.g. left by an editor, is ignored
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_wgsk_whitespace() {
+    let tmpdir = rp_tmpdir("rp-wgsk-whitespace");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+    let wgsk = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    fs::write(skdir.join("wgsk"), format!("  {wgsk}\r\n\n")).unwrap();
+
+    let shown = rp_shown(&rp_show_config(
+        &skdir,
+        &["print-wg-conf"],
+        &[(&pkdir, &[])],
+    ));
+    assert!(
+        shown.lines().any(|l| l == format!("PrivateKey = {wgsk}")),
+        "{shown}"
+    );
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that reload-interval is only taken from an arguments file, which it then watches
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_reload_interval() {
+    let tmpdir = rp_tmpdir("rp-reload-interval");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+
+    let args = tmpdir.join("rp.args");
+    fs::write(
+        &args,
+        format!(
+            "{} reload-interval 5\npeer {}\n",
+            skdir.display(),
+            pkdir.display()
+        ),
+    )
+    .unwrap();
+    let output = rp()
+        .arg("config")
+        .arg(&args)
+        .arg("show-config")
+        .output()
+        .unwrap();
+    let shown = rp_shown(&output);
+    assert!(shown.lines().any(|l| l == "reload-interval 5"), "{shown}");
+
+    // without an arguments file, there is nothing to reload
+    let stderr = rp_failed(&rp_show_config(
+        &skdir,
+        &["reload-interval", "5"],
+        &[(&pkdir, &[])],
+    ));
+    assert!(stderr.contains("requires an arguments file"), "{stderr}");
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that showpubkey wg-conf prints an [Interface] stanza, keeping the key out with redact
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_wg_conf_stanza() {
+    let tmpdir = rp_tmpdir("rp-wg-conf-stanza");
+    let (skdir, _) = rp_key_dirs(&tmpdir);
+    fs::write(skdir.join("wgsk"), format!("{TEST_WGPK}\n")).unwrap();
+
+    let stanza = |redact: bool| {
+        let mut cmd = rp();
+        cmd.arg("showpubkey").arg(&skdir).arg("wg-conf");
+        if redact {
+            cmd.arg("redact");
+        }
+        let output = cmd.output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let stdout = stanza(false);
+    assert_eq!(stdout.lines().next(), Some("[Interface]"), "{stdout}");
+    assert!(
+        stdout
+            .lines()
+            .any(|l| l == format!("PrivateKey = {TEST_WGPK}")),
+        "{stdout}"
+    );
+    let stdout = stanza(true);
+    assert!(!stdout.contains(TEST_WGPK), "{stdout}");
+    assert!(stdout.contains("redacted"), "{stdout}");
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that rp diff reports the changed settings of two arguments files, peer by peer
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_diff() {
+    let tmpdir = rp_tmpdir("rp-diff");
+    let (skdir, _) = rp_key_dirs(&tmpdir);
+    for (peer, wgpk) in [
+        ("a", TEST_WGPK),
+        ("b", "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="),
+    ] {
+        let pkdir = tmpdir.join(peer);
+        fs::create_dir_all(&pkdir).unwrap();
+        fs::write(pkdir.join("wgpk"), format!("{wgpk}\n")).unwrap();
+        fs::write(pkdir.join("pqpk"), peer).unwrap();
+    }
+
+    let (sk, dir) = (skdir.display(), tmpdir.display());
+    let old = tmpdir.join("old.args");
+    let new = tmpdir.join("new.args");
+    fs::write(
+        &old,
+        format!("{sk} listen 0.0.0.0:9999\npeer {dir}/a name a endpoint 10.0.0.1:9999\n"),
+    )
+    .unwrap();
+    fs::write(
+        &new,
+        format!(
+            "{sk} dev rp1 listen 0.0.0.0:9999\n\
+            peer {dir}/a name a endpoint 10.0.0.2:9999\npeer {dir}/b name b\n"
+        ),
+    )
+    .unwrap();
+
+    let output = rp().arg("diff").arg(&old).arg(&new).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines[0], "dev: rosenpass0 -> rp1");
+    assert_eq!(lines[1], "peer a:");
+    assert_eq!(lines[2], "  endpoint: 10.0.0.1:9999 -> 10.0.0.2:9999");
+    assert_eq!(
+        lines[3],
+        "  wireguard-endpoint: 10.0.0.1:10000 -> 10.0.0.2:10000"
+    );
+    assert_eq!(lines[4], "peer b: added");
+
+    let output = rp().arg("diff").arg(&old).arg(&old).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "no differences\n");
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that a running exchange answers status requests on its control socket
+#[cfg(unix)]
+#[test]
+fn check_control_socket() {
+    use std::time::Instant;
+
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("control-socket");
+    let _ = fs::remove_dir_all(&tmpdir);
+    fs::create_dir_all(&tmpdir).unwrap();
+
+    for name in ["own", "peer"] {
+        let output = test_bin::get_test_bin(BIN)
+            .args(["gen-keys", "--secret-key"])
+            .arg(tmpdir.join(format!("{name}-sk")))
+            .arg("--public-key")
+            .arg(tmpdir.join(format!("{name}-pk")))
+            .output()
+            .expect("Failed to start {BIN}");
+        assert!(output.status.success());
+    }
+
+    let socket = tmpdir.join("control");
+    let port = find_udp_socket();
+    let mut server = test_bin::get_test_bin(BIN)
+        .args(["exchange", "secret-key"])
+        .arg(tmpdir.join("own-sk"))
+        .arg("public-key")
+        .arg(tmpdir.join("own-pk"))
+        .args(["listen", &format!("127.0.0.1:{port}"), "control-socket"])
+        .arg(&socket)
+        .args(["peer", "public-key"])
+        .arg(tmpdir.join("peer-pk"))
+        .args(["name", "other"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start {BIN}");
+
+    let started = Instant::now();
+    while !socket.exists() && started.elapsed() < Duration::from_secs(20) {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    let query = |request: &str| {
+        let output = test_bin::get_test_bin(BIN)
+            .arg("status")
+            .arg(&socket)
+            .arg(request)
+            .output()
+            .expect("Failed to start {BIN}");
+        (
+            output.status.success(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        )
+    };
+
+    let (ok, status) = query("status");
+    assert!(ok, "{status}");
+    assert!(status.contains("peers 1\n"), "{status}");
+    assert!(status.contains("exchanges 0\n"), "{status}");
+
+    let (ok, peers) = query("peers");
+    assert!(ok, "{peers}");
+    assert_eq!(
+        peers,
+        "peer other key-age none handshake-attempts 0 exchanges 0 endpoint none last-error none\n"
+    );
+
+    let (ok, _) = query("bogus");
+    assert!(!ok);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that peers sharing their keys are rejected, or merged on request
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_duplicate_peers() {
+    let tmpdir = rp_tmpdir("rp-duplicate-peers");
+    let (skdir, first) = rp_key_dirs(&tmpdir);
+    let second = tmpdir.join("second");
+    fs::create_dir_all(&second).unwrap();
+    for file in ["wgpk", "pqpk"] {
+        fs::copy(first.join(file), second.join(file)).unwrap();
+    }
+
+    let show = |options: &[&str]| {
+        rp_show_config(
+            &skdir,
+            options,
+            &[
+                (&first, &["name", "a", "allowed-ips", "10.0.0.1/32"]),
+                (&second, &["name", "b", "allowed-ips", "10.0.0.2/32"]),
+            ],
+        )
+    };
+
+    let stderr = rp_failed(&show(&[]));
+    assert!(
+        stderr.contains("Peers \"a\"") && stderr.contains("and \"b\""),
+        "{stderr}"
+    );
+
+    let shown = rp_shown(&show(&["merge-duplicate-peers", "print-wg-conf"]));
+    assert!(
+        shown.contains("AllowedIPs = 10.0.0.1/32, 10.0.0.2/32"),
+        "{shown}"
+    );
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that key-name renames the key files rp looks for
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_key_names() {
+    let tmpdir = rp_tmpdir("rp-key-names");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+    fs::rename(skdir.join("pqsk"), skdir.join("rosenpass-secret.key")).unwrap();
+    for dir in [&skdir, &pkdir] {
+        fs::rename(dir.join("pqpk"), dir.join("rosenpass-public.key")).unwrap();
+    }
+
+    let output = rp()
+        .args(["key-name", "pqsk", "rosenpass-secret.key"])
+        .args(["key-name", "pqpk", "rosenpass-public.key"])
+        .arg("show-config")
+        .arg(&skdir)
+        .arg("peer")
+        .arg(&pkdir)
+        .output()
+        .unwrap();
+    let shown = rp_shown(&output);
+    assert!(
+        shown.contains(&format!(
+            "pqsk {}",
+            skdir.join("rosenpass-secret.key").display()
+        )),
+        "{shown}"
+    );
+    assert!(
+        shown.contains(&format!(
+            "pqpk {}",
+            pkdir.join("rosenpass-public.key").display()
+        )),
+        "{shown}"
+    );
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that the cleanup of a failed exchange runs exactly once, whether a setup step or the
+// daemon fails
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_cleanup_once() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmpdir = rp_tmpdir("rp-cleanup-once");
+    let (skdir, pkdir) = rp_key_dirs(&tmpdir);
+    // ip and wg only record their calls; wg fails if asked to. The interface is lo, which
+    // exists, so the cleanup tries to remove it, but only with this fake ip
+    let bin_dir = tmpdir.join("bin");
+    fs::create_dir_all(&bin_dir).unwrap();
+    let calls = tmpdir.join("calls");
+    for (tool, status) in [("ip", "0"), ("wg", "${RP_TEST_WG_STATUS}")] {
+        let script = bin_dir.join(tool);
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"{tool} $*\" >> '{}'\nexit {status}\n",
+                calls.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    // wg failing stops the setup, the empty secret key stops the daemon
+    for wg_status in ["1", "0"] {
+        let _ = fs::remove_file(&calls);
+        let output = rp_with_tools(&bin_dir)
+            .env("RP_TEST_WG_STATUS", wg_status)
+            .args(["exchange"])
+            .arg(&skdir)
+            .args(["dev", "lo", "peer"])
+            .arg(&pkdir)
+            .output()
+            .unwrap();
+        let logged = fs::read_to_string(&calls).unwrap();
+        assert!(
+            !output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            logged
+                .lines()
+                .filter(|l| *l == "ip link del dev lo")
+                .count(),
+            1,
+            "{logged}"
+        );
+    }
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that a genkey failing part way removes the directory it created
+#[cfg(target_os = "linux")]
+#[test]
+fn check_rp_genkey_rollback() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmpdir = rp_tmpdir("rp-genkey-rollback");
+    let bin_dir = tmpdir.join("bin");
+    fs::create_dir_all(&bin_dir).unwrap();
+    // a wg that fails, so genkey stops after creating the directory
+    let wg = bin_dir.join("wg");
+    fs::write(&wg, "#!/bin/sh\nexit 1\n").unwrap();
+    fs::set_permissions(&wg, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let skdir = tmpdir.join("secret");
+    let output = rp_with_tools(&bin_dir)
+        .arg("genkey")
+        .arg(&skdir)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(
+        !skdir.exists(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    fs::remove_dir_all(&tmpdir).unwrap();
+}